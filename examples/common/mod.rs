@@ -0,0 +1,216 @@
+//! Arguments shared by all of the examples for configuring the load.
+
+use std::time::Duration;
+
+use loadbench::{
+    arrival::Arrival, mode::ThinkTime, LoadOptions, LoadOptionsBuilder, MissedTickBehavior,
+    OverloadPolicy, Pacing, PhaseLength, RunReport,
+};
+
+#[derive(clap::Args)]
+pub struct LoadArgs {
+    #[clap(long, default_value = "100")]
+    rate: u64,
+    /// Stop after this many requests, defaults to 1000 if no duration is given.
+    #[clap(long)]
+    total: Option<u64>,
+    /// Stop after this many seconds.
+    #[clap(long)]
+    duration_secs: Option<f64>,
+    /// Leave the first seconds of the run out of the stats.
+    #[clap(long)]
+    warmup_secs: Option<f64>,
+
+    #[clap(long, default_value = "0")]
+    initial_clients: u32,
+    #[clap(long)]
+    max_clients: Option<u32>,
+    /// Most requests that each client executes at once.
+    #[clap(long, default_value = "1")]
+    client_concurrency: u32,
+    /// What to do with inputs when all of the clients are busy.
+    #[clap(long, value_enum, default_value = "block")]
+    overload: OverloadArg,
+    /// Number of inputs to queue with the queue overload policy.
+    #[clap(long, default_value = "100")]
+    queue_depth: usize,
+    /// What to do when the generator falls behind its schedule.
+    #[clap(long, value_enum, default_value = "burst")]
+    missed_ticks: MissedTicksArg,
+    /// Spin until each input is due rather than sleeping, for accurate spacing at high rates.
+    #[clap(long)]
+    spin: bool,
+    /// Split the load across this many independent generators.
+    #[clap(long, default_value = "1")]
+    shards: u32,
+    /// Run each shard on its own thread.
+    #[clap(long)]
+    shard_threads: bool,
+
+    #[clap(long, value_enum, default_value = "constant")]
+    arrival: ArrivalArg,
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Run in a closed loop with this many clients, instead of at the rate.
+    #[clap(long)]
+    closed_loop_clients: Option<u32>,
+    /// Time each closed loop client waits between requests.
+    #[clap(long, default_value = "0")]
+    think_time_ms: f64,
+
+    /// Record requests taking longer than this as timed out.
+    #[clap(long)]
+    request_timeout_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum OverloadArg {
+    /// Wait for a free client.
+    Block,
+    /// Drop the input.
+    Drop,
+    /// Queue the input, dropping it if the queue is full.
+    Queue,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum MissedTicksArg {
+    /// Send late inputs straight away.
+    Burst,
+    /// Push the rest of the schedule back.
+    Delay,
+    /// Skip the late inputs.
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ArrivalArg {
+    /// Evenly spaced requests.
+    Constant,
+    /// Exponentially distributed gaps between requests.
+    Poisson,
+}
+
+impl LoadArgs {
+    pub fn builder(&self) -> LoadOptionsBuilder {
+        let arrival = match self.arrival {
+            ArrivalArg::Constant => Arrival::Constant,
+            ArrivalArg::Poisson => Arrival::Poisson,
+        };
+        let overload = match self.overload {
+            OverloadArg::Block => OverloadPolicy::Block,
+            OverloadArg::Drop => OverloadPolicy::Drop,
+            OverloadArg::Queue => OverloadPolicy::Queue {
+                depth: self.queue_depth,
+            },
+        };
+        let mut builder = LoadOptions::builder()
+            .rate(self.rate)
+            .handle_signals(true)
+            .initial_clients(self.initial_clients)
+            .max_clients(self.max_clients)
+            .client_concurrency(self.client_concurrency)
+            .overload(overload)
+            .missed_ticks(match self.missed_ticks {
+                MissedTicksArg::Burst => MissedTickBehavior::Burst,
+                MissedTicksArg::Delay => MissedTickBehavior::Delay,
+                MissedTicksArg::Skip => MissedTickBehavior::Skip,
+            })
+            .pacing(if self.spin {
+                Pacing::Spin
+            } else {
+                Pacing::Sleep
+            })
+            .shards(self.shards)
+            .shard_threads(self.shard_threads)
+            .arrival(arrival);
+        match (self.total, self.duration_secs) {
+            (None, None) => builder = builder.total(1000),
+            (total, duration_secs) => {
+                if let Some(total) = total {
+                    builder = builder.total(total);
+                }
+                if let Some(duration_secs) = duration_secs {
+                    builder = builder.duration(secs_arg("duration", duration_secs));
+                }
+            }
+        }
+        if let Some(warmup_secs) = self.warmup_secs {
+            builder = builder.warmup(PhaseLength::Duration(secs_arg("warmup", warmup_secs)));
+        }
+        if let Some(clients) = self.closed_loop_clients {
+            let think_time = secs_arg("think time", self.think_time_ms / 1000.);
+            builder = builder.closed_loop(clients, ThinkTime::Constant(think_time));
+        }
+        if let Some(request_timeout_ms) = self.request_timeout_ms {
+            builder =
+                builder.request_timeout(secs_arg("request timeout", request_timeout_ms / 1000.));
+        }
+        if let Some(seed) = self.seed {
+            builder = builder.seed(seed);
+        }
+        builder
+    }
+
+    /// Build the options, exiting with a message if they are invalid.
+    pub fn options(&self) -> LoadOptions {
+        self.builder().build().unwrap_or_else(|error| {
+            eprintln!("Invalid load options: {error}");
+            std::process::exit(2)
+        })
+    }
+}
+
+/// Convert an argument in seconds to a duration, exiting with a message if it is negative or too
+/// large.
+fn secs_arg(name: &str, secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or_else(|error| {
+        eprintln!("Invalid {name}: {error}");
+        std::process::exit(2)
+    })
+}
+
+/// Print the generator's side of the run.
+pub fn print_report(report: &RunReport) {
+    println!("Stop reason: {:?}", report.stop_reason);
+    println!(
+        "Inputs generated: {}, sent: {}, dropped: {}",
+        report.inputs_generated, report.inputs_sent, report.inputs_dropped
+    );
+    println!(
+        "Clients spawned: {}, peak in flight: {}",
+        report.clients_spawned, report.peak_in_flight
+    );
+    if report.retries_skipped > 0 {
+        println!(
+            "Retries skipped, without an input to retry with: {}",
+            report.retries_skipped
+        );
+    }
+    if let Some(schedule) = &report.schedule {
+        println!(
+            "Target rate (req/s): {:.1}, achieved rate (req/s): {:.1}",
+            schedule.target_rate, schedule.achieved_rate
+        );
+        println!("Missed ticks: {}", schedule.missed_ticks);
+        println!(
+            "Schedule lag p50: {:?}, p99: {:?}, max: {:?}",
+            schedule.lag.percentile(0.5),
+            schedule.lag.percentile(0.99),
+            schedule.lag.max()
+        );
+        println!(
+            "Inter-arrival jitter p50: {:?}, p99: {:?}, max: {:?}",
+            schedule.jitter.percentile(0.5),
+            schedule.jitter.percentile(0.99),
+            schedule.jitter.max()
+        );
+    }
+    for failed in &report.failed_joins {
+        match failed.client {
+            Some(client) => println!("Client {client} failed to join: {}", failed.error),
+            None => println!("Shard {} failed to join: {}", failed.shard, failed.error),
+        }
+    }
+}
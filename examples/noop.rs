@@ -1,3 +1,5 @@
+mod common;
+
 use clap::Parser;
 use tracing::metadata::LevelFilter;
 use tracing_subscriber::layer::SubscriberExt;
@@ -5,23 +7,10 @@ use tracing_subscriber::util::SubscriberInitExt;
 
 use loadbench::{
     client::{Dispatcher, DispatcherGenerator},
-    generate_load,
-    input::InputGenerator,
+    generate_load_sharded,
     output_sink::StatsOutputSink,
 };
 
-pub struct NoopInputGenerator;
-
-impl InputGenerator for NoopInputGenerator {
-    type Input = ();
-
-    fn close(self) {}
-
-    fn next(&mut self) -> Option<Self::Input> {
-        Some(())
-    }
-}
-
 struct NoopDispatcherGenerator;
 
 impl DispatcherGenerator for NoopDispatcherGenerator {
@@ -43,28 +32,22 @@ impl Dispatcher for NoopDispatcher {
     async fn execute(&mut self, _: Self::Input) -> Result<Self::Output, String> {
         Ok(())
     }
+
+    fn concurrent_handle(&self) -> Option<Self> {
+        Some(self.clone())
+    }
 }
 
 #[derive(Parser)]
 struct Args {
-    #[clap(long, default_value = "100")]
-    rate: u64,
-    #[clap(long, default_value = "1000")]
-    total: u64,
-
-    #[clap(long, default_value = "0")]
-    initial_clients: u64,
-    #[clap(long)]
-    max_clients: Option<u32>,
+    #[clap(flatten)]
+    load: common::LoadArgs,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let input = NoopInputGenerator {};
-
-    let dispatcher = NoopDispatcherGenerator {};
     let mut writer = StatsOutputSink::default();
 
     tracing_subscriber::registry()
@@ -76,16 +59,14 @@ async fn main() {
         )
         .init();
 
-    generate_load(
-        args.rate,
-        args.initial_clients,
-        args.total,
-        args.max_clients,
-        input,
-        dispatcher,
+    let report = generate_load_sharded(
+        args.load.options(),
+        |_| std::iter::repeat(()),
+        |_| NoopDispatcherGenerator {},
         &mut writer,
     )
     .await;
 
     writer.summary();
+    common::print_report(&report);
 }
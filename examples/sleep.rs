@@ -1,3 +1,5 @@
+mod common;
+
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
@@ -7,7 +9,7 @@ use tracing_subscriber::util::SubscriberInitExt;
 
 use loadbench::{
     client::{Dispatcher, DispatcherGenerator},
-    generate_load,
+    generate_load_sharded,
     input::InputGenerator,
     output_sink::StatsOutputSink,
 };
@@ -57,15 +59,8 @@ pub struct SleepOutput {}
 
 #[derive(Parser)]
 struct Args {
-    #[clap(long, default_value = "100")]
-    rate: u64,
-    #[clap(long, default_value = "1000")]
-    total: u64,
-
-    #[clap(long, default_value = "0")]
-    initial_clients: u64,
-    #[clap(long)]
-    max_clients: Option<u32>,
+    #[clap(flatten)]
+    load: common::LoadArgs,
 
     #[clap(long, default_value = "100")]
     sleep_ms: f64,
@@ -75,11 +70,6 @@ struct Args {
 async fn main() {
     let args = Args::parse();
 
-    let sleep_input = SleepInputGenerator {
-        milliseconds: args.sleep_ms,
-    };
-
-    let sleep_dispatcher = SleepDispatcherGenerator {};
     let mut writer = StatsOutputSink::default();
 
     tracing_subscriber::registry()
@@ -91,16 +81,16 @@ async fn main() {
         )
         .init();
 
-    generate_load(
-        args.rate,
-        args.initial_clients,
-        args.total,
-        args.max_clients,
-        sleep_input,
-        sleep_dispatcher,
+    let report = generate_load_sharded(
+        args.load.options(),
+        |_| SleepInputGenerator {
+            milliseconds: args.sleep_ms,
+        },
+        |_| SleepDispatcherGenerator {},
         &mut writer,
     )
     .await;
 
     writer.summary();
+    common::print_report(&report);
 }
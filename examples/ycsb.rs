@@ -1,7 +1,9 @@
+mod common;
+
 use async_trait::async_trait;
 use clap::Parser;
 use loadbench::client::{Dispatcher, DispatcherGenerator};
-use loadbench::generate_load;
+use loadbench::generate_load_sharded;
 use loadbench::{input::InputGenerator, output_sink::StatsOutputSink};
 use rand::SeedableRng;
 use rand::{distributions::Alphanumeric, rngs::StdRng, Rng};
@@ -21,6 +23,11 @@ pub struct YcsbInputGenerator {
     pub field_value_length: usize,
     pub operation_rng: StdRng,
     pub max_record_index: u32,
+    /// Index of the next record to insert.
+    pub insert_index: u32,
+    /// How far apart the records inserted by this generator are, so that generators running
+    /// alongside each other insert different records.
+    pub insert_stride: u32,
     pub request_distribution: RequestDistribution,
 }
 
@@ -37,8 +44,10 @@ pub enum RequestDistribution {
 impl YcsbInputGenerator {
     pub fn new_record_key(&mut self) -> String {
         // TODO: may not want incremental inserts
-        self.max_record_index += 1;
-        format!("user{:08}", self.max_record_index)
+        let index = self.insert_index;
+        self.insert_index += self.insert_stride;
+        self.max_record_index = self.max_record_index.max(index);
+        format!("user{:08}", index)
     }
 
     pub fn existing_record_key(&mut self) -> String {
@@ -120,7 +129,6 @@ impl InputGenerator for YcsbInputGenerator {
             2 => YcsbInput::Insert {
                 record_key: self.new_record_key(),
                 fields: (0..self.fields_per_record)
-                    .into_iter()
                     .map(|i| (Self::field_key(i), self.field_value()))
                     .collect(),
             },
@@ -174,15 +182,8 @@ impl Dispatcher for YcsbDispatcher {
 
 #[derive(Parser)]
 struct Args {
-    #[clap(long, default_value = "100")]
-    rate: u64,
-    #[clap(long, default_value = "1000")]
-    total: u64,
-
-    #[clap(long, default_value = "0")]
-    initial_clients: u64,
-    #[clap(long)]
-    max_clients: Option<u32>,
+    #[clap(flatten)]
+    load: common::LoadArgs,
 
     #[clap(long, default_value = "1")]
     read_weight: u32,
@@ -208,7 +209,11 @@ struct Args {
 async fn main() {
     let args = Args::parse();
 
-    let input = YcsbInputGenerator {
+    let options = args.load.options();
+    let seed = options.seed().unwrap_or_else(rand::random);
+    let shards = options.shards();
+    // each shard gets its own seed and inserts every `shards`th record after the existing ones
+    let inputs = |shard: u32| YcsbInputGenerator {
         read_weight: args.read_weight,
         scan_weight: args.scan_weight,
         insert_weight: args.insert_weight,
@@ -216,12 +221,13 @@ async fn main() {
         read_all_fields: args.read_all_fields,
         fields_per_record: args.fields_per_record,
         field_value_length: args.field_value_length,
-        operation_rng: StdRng::from_entropy(),
+        operation_rng: StdRng::seed_from_u64(seed.wrapping_add(shard as u64)),
         max_record_index: args.max_record_index,
+        insert_index: args.max_record_index + 1 + shard,
+        insert_stride: shards,
         request_distribution: args.request_distribution,
     };
 
-    let dispatcher = YcsbDispatcherGenerator {};
     let mut writer = StatsOutputSink::default();
 
     tracing_subscriber::registry()
@@ -233,16 +239,9 @@ async fn main() {
         )
         .init();
 
-    generate_load(
-        args.rate,
-        args.initial_clients,
-        args.total,
-        args.max_clients,
-        input,
-        dispatcher,
-        &mut writer,
-    )
-    .await;
+    let report =
+        generate_load_sharded(options, inputs, |_| YcsbDispatcherGenerator {}, &mut writer).await;
 
     writer.summary();
+    common::print_report(&report);
 }
@@ -0,0 +1,227 @@
+//! Arrival processes decide the gaps between requests in the open-loop generator.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Produces the gap to wait before each request is sent.
+pub trait ArrivalProcess: Send {
+    /// The gap before the next request, for a target rate in requests per second.
+    fn next_gap(&mut self, rate: f64) -> Duration;
+}
+
+impl<F: FnMut(f64) -> Duration + Send> ArrivalProcess for F {
+    fn next_gap(&mut self, rate: f64) -> Duration {
+        self(rate)
+    }
+}
+
+/// Evenly spaced arrivals.
+#[derive(Debug, Clone, Default)]
+pub struct Constant;
+
+impl ArrivalProcess for Constant {
+    fn next_gap(&mut self, rate: f64) -> Duration {
+        Duration::from_secs_f64(1. / rate)
+    }
+}
+
+/// Arrivals from a Poisson process, with exponentially distributed gaps.
+#[derive(Debug, Clone)]
+pub struct Poisson {
+    rng: StdRng,
+}
+
+impl Poisson {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl ArrivalProcess for Poisson {
+    fn next_gap(&mut self, rate: f64) -> Duration {
+        // inverse transform sampling, `1 - u` is in (0, 1] so the log is finite
+        let u: f64 = self.rng.gen();
+        Duration::from_secs_f64(-(1. - u).ln() / rate)
+    }
+}
+
+/// Evenly spaced arrivals, each moved by a uniformly random fraction of the gap.
+#[derive(Debug, Clone)]
+pub struct UniformJitter {
+    jitter: f64,
+    rng: StdRng,
+}
+
+impl UniformJitter {
+    /// `jitter` is the largest fraction of the gap to move by, between 0 and 1.
+    pub fn new(jitter: f64, seed: u64) -> Self {
+        Self {
+            jitter,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl ArrivalProcess for UniformJitter {
+    fn next_gap(&mut self, rate: f64) -> Duration {
+        let offset = if self.jitter > 0. {
+            self.rng.gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.
+        };
+        Duration::from_secs_f64((1. + offset) / rate)
+    }
+}
+
+/// Bursty arrivals, alternating between sending for the `on` period and being silent for the
+/// `off` period.
+///
+/// During the `on` period the inner process is run at a higher rate so that the average rate over
+/// a whole cycle matches the target.
+#[derive(Debug, Clone)]
+pub struct OnOff<P> {
+    on: Duration,
+    off: Duration,
+    inner: P,
+    /// Position within the current cycle.
+    elapsed: Duration,
+}
+
+impl<P: ArrivalProcess> OnOff<P> {
+    /// # Panics
+    ///
+    /// If `on` is zero or a whole cycle of `on` and `off` is too long to represent.
+    pub fn new(on: Duration, off: Duration, inner: P) -> Self {
+        assert!(!on.is_zero(), "OnOff on period must be greater than zero");
+        assert!(
+            on.checked_add(off).is_some(),
+            "OnOff cycle of {on:?} on and {off:?} off is too long to represent"
+        );
+        Self {
+            on,
+            off,
+            inner,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl<P: ArrivalProcess> ArrivalProcess for OnOff<P> {
+    fn next_gap(&mut self, rate: f64) -> Duration {
+        let cycle = self.on + self.off;
+        let burst_rate = rate * cycle.as_secs_f64() / self.on.as_secs_f64();
+        let mut gap = self.inner.next_gap(burst_rate);
+        // skip over the off period if the next arrival would land in it
+        if self.elapsed + gap >= self.on {
+            gap += self.off;
+        }
+        self.elapsed =
+            Duration::from_nanos(((self.elapsed + gap).as_nanos() % cycle.as_nanos()) as u64);
+        gap
+    }
+}
+
+/// Which arrival process a run should use.
+#[derive(Clone, Default)]
+pub enum Arrival {
+    /// Use [`Constant`].
+    #[default]
+    Constant,
+    /// Use [`Poisson`].
+    Poisson,
+    /// Use [`UniformJitter`] with the given jitter fraction.
+    UniformJitter { jitter: f64 },
+    /// Use [`OnOff`] with Poisson arrivals during the `on` period.
+    OnOff { on: Duration, off: Duration },
+    /// Build a custom process from the run's seed.
+    Custom(Arc<dyn Fn(u64) -> Box<dyn ArrivalProcess> + Send + Sync>),
+}
+
+impl Arrival {
+    /// Build the process, seeding any randomness with `seed`.
+    pub fn build(&self, seed: u64) -> Box<dyn ArrivalProcess> {
+        match self {
+            Self::Constant => Box::new(Constant),
+            Self::Poisson => Box::new(Poisson::new(seed)),
+            Self::UniformJitter { jitter } => Box::new(UniformJitter::new(*jitter, seed)),
+            Self::OnOff { on, off } => Box::new(OnOff::new(*on, *off, Poisson::new(seed))),
+            Self::Custom(build) => build(seed),
+        }
+    }
+}
+
+impl fmt::Debug for Arrival {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Constant => write!(f, "Constant"),
+            Self::Poisson => write!(f, "Poisson"),
+            Self::UniformJitter { jitter } => f
+                .debug_struct("UniformJitter")
+                .field("jitter", jitter)
+                .finish(),
+            Self::OnOff { on, off } => f
+                .debug_struct("OnOff")
+                .field("on", on)
+                .field("off", off)
+                .finish(),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mean_gap(process: &mut dyn ArrivalProcess, rate: f64, count: u32) -> f64 {
+        let total: Duration = (0..count).map(|_| process.next_gap(rate)).sum();
+        total.as_secs_f64() / count as f64
+    }
+
+    #[test]
+    fn test_constant_gap() {
+        assert_eq!(Constant.next_gap(1000.), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_seeded_processes_are_reproducible() {
+        for arrival in [
+            Arrival::Poisson,
+            Arrival::UniformJitter { jitter: 0.5 },
+            Arrival::OnOff {
+                on: Duration::from_millis(10),
+                off: Duration::from_millis(30),
+            },
+        ] {
+            let mut a = arrival.build(7);
+            let mut b = arrival.build(7);
+            for _ in 0..100 {
+                assert_eq!(a.next_gap(100.), b.next_gap(100.), "{arrival:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_mean_rate_matches_target() {
+        for arrival in [
+            Arrival::Poisson,
+            Arrival::UniformJitter { jitter: 0.5 },
+            Arrival::OnOff {
+                on: Duration::from_millis(10),
+                off: Duration::from_millis(30),
+            },
+        ] {
+            let mean = mean_gap(&mut *arrival.build(1), 1000., 100_000);
+            assert!((mean - 0.001).abs() < 0.0001, "{arrival:?} mean gap {mean}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "on period must be greater than zero")]
+    fn test_on_off_rejects_zero_on_period() {
+        OnOff::new(Duration::ZERO, Duration::ZERO, Constant);
+    }
+}
@@ -1,7 +1,29 @@
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    task::Poll,
+    time::Duration,
+};
+
 use serde::Serialize;
-use tracing::{debug, trace};
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
 
-use crate::output::Output;
+use crate::{
+    clock::Clock,
+    loadgen::sleep_until_some,
+    mode::ThinkTimeSampler,
+    options::PanicPolicy,
+    output::{Output, Phase},
+    report::Counters,
+    retry::BackoffSampler,
+    shutdown::{ShutdownState, ShutdownWatch},
+};
 use async_trait::async_trait;
 
 pub trait DispatcherGenerator {
@@ -14,36 +36,461 @@ pub trait Dispatcher: Send + 'static {
     type Input: Send;
     type Output: Send + Default;
     async fn execute(&mut self, request: Self::Input) -> Result<Self::Output, String>;
+
+    /// Execute a batch of inputs together, such as with a bulk insert, returning a result for
+    /// each input in the same order.
+    ///
+    /// Clients only call this when batching is set in the options. The default executes the
+    /// inputs one after another.
+    async fn execute_batch(
+        &mut self,
+        requests: Vec<Self::Input>,
+    ) -> Vec<Result<Self::Output, String>> {
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            results.push(self.execute(request).await);
+        }
+        results
+    }
+
+    /// The timeout for executing this input, overriding the request timeout from the options.
+    ///
+    /// Returning `None` uses the request timeout from the options, if there is one. A batch is
+    /// given up on after the longest timeout of its inputs.
+    fn timeout(&self, _input: &Self::Input) -> Option<Duration> {
+        None
+    }
+
+    /// A copy of the input to retry it with, if the retry policy allows another attempt.
+    ///
+    /// Returning `None`, the default, leaves the input to be executed only once, whatever the
+    /// retry policy. Dispatchers with inputs that can be cloned can return a clone.
+    fn retry_input(&self, _input: &Self::Input) -> Option<Self::Input> {
+        None
+    }
+
+    /// Another handle to this dispatcher, for executing requests alongside the ones it is
+    /// already executing, such as over a pipelined or multiplexed connection.
+    ///
+    /// Clients use handles to run up to the configured client concurrency of requests at once.
+    /// Returning `None`, the default, limits clients to one request at a time.
+    fn concurrent_handle(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// Identifies the client that a task is executing requests for.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientId {
+    pub(crate) id: u32,
+    /// Iterations of the client, shared by all of its tasks.
+    pub(crate) iterations: Arc<AtomicU32>,
+}
+
+/// An input to be executed by a client, along with when the load generator scheduled it, if it
+/// was scheduled.
+pub(crate) struct Request<I> {
+    pub(crate) input: I,
+    pub(crate) scheduled_ns: Option<i64>,
+    pub(crate) phase: Phase,
+    pub(crate) session: Option<u64>,
+}
+
+/// Where a client task takes its inputs from.
+pub(crate) struct ClientInputs<I> {
+    /// Inputs that any client can take.
+    pub(crate) shared: async_channel::Receiver<Request<I>>,
+    /// Inputs of the sessions assigned to this task.
+    pub(crate) sessions: async_channel::Receiver<Request<I>>,
 }
 
-pub(crate) async fn run<D: Dispatcher>(
-    receiver: async_channel::Receiver<D::Input>,
-    client: u32,
-    mut dispatcher: D,
-) -> Vec<Output<D::Output>>
-where
-    D::Output: Serialize + Default,
+impl<I> ClientInputs<I> {
+    /// Take the next input from either queue, or `None` once both have closed and emptied.
+    async fn recv(&self) -> Option<Request<I>> {
+        tokio::select! {
+            biased;
+            request = self.sessions.recv() => match request {
+                Ok(request) => Some(request),
+                Err(_) => self.shared.recv().await.ok(),
+            },
+            request = self.shared.recv() => match request {
+                Ok(request) => Some(request),
+                Err(_) => self.sessions.recv().await.ok(),
+            },
+        }
+    }
+}
+
+/// What the clients of a run share with each other.
+pub(crate) struct ClientContext<O> {
+    pub(crate) shutdown: ShutdownWatch,
+    pub(crate) outputs: async_channel::Sender<Output<O>>,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) counters: Arc<Counters>,
+    /// Clients of the same shard waiting for their next input.
+    pub(crate) idle: Arc<AtomicU32>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) on_panic: PanicPolicy,
+    pub(crate) batch_size: u32,
+    pub(crate) batch_wait: Duration,
+}
+
+impl<O> Clone for ClientContext<O> {
+    fn clone(&self) -> Self {
+        Self {
+            shutdown: self.shutdown.clone(),
+            outputs: self.outputs.clone(),
+            request_timeout: self.request_timeout,
+            counters: Arc::clone(&self.counters),
+            idle: Arc::clone(&self.idle),
+            clock: Arc::clone(&self.clock),
+            on_panic: self.on_panic,
+            batch_size: self.batch_size,
+            batch_wait: self.batch_wait,
+        }
+    }
+}
+
+/// Run one of a client's tasks, taking inputs from the receiver until it closes.
+///
+/// The dispatcher generator is only used to replace the dispatcher if it panics.
+pub(crate) async fn run<G: DispatcherGenerator>(
+    inputs: ClientInputs<<G::Dispatcher as Dispatcher>::Input>,
+    client: ClientId,
+    mut dispatcher: G::Dispatcher,
+    dispatcher_generator: Arc<Mutex<G>>,
+    mut think_time: ThinkTimeSampler,
+    mut backoff: BackoffSampler,
+    context: ClientContext<<G::Dispatcher as Dispatcher>::Output>,
+) where
+    <G::Dispatcher as Dispatcher>::Output: Serialize + Default,
 {
-    let mut all_outputs = Vec::new();
-    let mut iteration = 0;
-    while let Ok(input) = receiver.recv().await {
-        let mut output = Output::start(client, iteration);
-        let res = dispatcher.execute(input).await;
-        output.stop();
-        match res {
-            Ok(data) => {
-                *output.data_mut() = data;
+    let ClientContext {
+        mut shutdown,
+        outputs,
+        request_timeout,
+        counters,
+        idle,
+        clock,
+        on_panic,
+        batch_size,
+        batch_wait,
+    } = context;
+    let ClientId {
+        id: client,
+        iterations,
+    } = client;
+    let batching = batch_size > 1;
+    let mut warned_no_retry_input = false;
+    'client: loop {
+        let request = tokio::select! {
+            biased;
+            _ = shutdown.reached(ShutdownState::Cancelled) => break,
+            request = inputs.recv() => request,
+        };
+        let Some(request) = request else {
+            break;
+        };
+        // the client stays idle while gathering, so that the rest of the batch comes to it
+        let mut requests = vec![request];
+        if batching {
+            // a wait too long to represent never runs out
+            let deadline = Instant::now().checked_add(batch_wait);
+            while requests.len() < batch_size as usize {
+                tokio::select! {
+                    biased;
+                    // what has been gathered gets recorded as cancelled
+                    _ = shutdown.reached(ShutdownState::Cancelled) => break,
+                    // inputs that are already waiting are taken even once the wait is up
+                    request = inputs.recv() => match request {
+                        Some(request) => requests.push(request),
+                        None => break,
+                    },
+                    _ = sleep_until_some(deadline) => break,
+                }
+            }
+        }
+        idle.fetch_sub(1, Ordering::Relaxed);
+
+        let size = requests.len();
+        let batch_id = batching.then(|| counters.batches.fetch_add(1, Ordering::Relaxed));
+        let start_ns = clock.now_ns();
+        let mut pending: Vec<_> = requests
+            .into_iter()
+            .map(|request| {
+                let iteration = iterations.fetch_add(1, Ordering::Relaxed);
+                let mut output =
+                    Output::start_at(client, iteration, request.scheduled_ns, start_ns);
+                output.core.phase = request.phase;
+                output.core.batch_size = size as u32;
+                output.core.batch_id = batch_id;
+                output.core.session = request.session;
+                counters.start_request();
+                (output, request.input)
+            })
+            .collect();
+        let mut finished = Vec::with_capacity(size);
+        let mut cancelled = false;
+        // each attempt executes the inputs that are still being retried
+        while !pending.is_empty() {
+            let policy = backoff.policy();
+            let attempt = pending[0].0.core.attempts;
+            let (attempt_outputs, inputs): (Vec<_>, Vec<_>) = pending.drain(..).unzip();
+            let count = inputs.len();
+            // the dispatcher's own methods can panic too, which counts as a panicked attempt
+            let prepared = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let timeout = batch_timeout(&dispatcher, &inputs, request_timeout);
+                // only keep a copy if there could be another attempt
+                let retry_inputs = (attempt < policy.max_attempts).then(|| {
+                    inputs
+                        .iter()
+                        .map(|input| dispatcher.retry_input(input))
+                        .collect::<Vec<_>>()
+                });
+                (timeout, retry_inputs)
+            }));
+            let attempt_start_ns = clock.now_ns();
+            let (res, retry_inputs) = match prepared {
+                Ok((timeout, retry_inputs)) => {
+                    if let Some(copies) = &retry_inputs {
+                        if !warned_no_retry_input && copies.iter().any(Option::is_none) {
+                            warned_no_retry_input = true;
+                            warn!(%client, "Dispatcher gave no input to retry with, not retrying it");
+                        }
+                    }
+                    let res = tokio::select! {
+                        biased;
+                        _ = shutdown.reached(ShutdownState::Cancelled) => None,
+                        res = catch_unwind(execute(&mut dispatcher, inputs, batching, timeout)) => Some(res),
+                    };
+                    (res, retry_inputs)
+                }
+                Err(panic) => (Some(Err(panic)), None),
+            };
+            let end_ns = clock.now_ns();
+            let Some(res) = res else {
+                for mut output in attempt_outputs {
+                    output.error_at("cancelled during shutdown".to_owned(), end_ns);
+                    finished.push(output);
+                }
+                cancelled = true;
+                break;
+            };
+            let results: Vec<_> = match res {
+                Ok(Some(results)) if results.len() == count => {
+                    results.into_iter().map(Attempt::Done).collect()
+                }
+                Ok(Some(results)) => {
+                    let error = format!(
+                        "batch returned {} results for {count} inputs",
+                        results.len()
+                    );
+                    (0..count)
+                        .map(|_| Attempt::Done(Err(error.clone())))
+                        .collect()
+                }
+                Ok(None) => (0..count).map(|_| Attempt::TimedOut).collect(),
+                Err(panic) => {
+                    let message = panic_message(&*panic).to_owned();
+                    warn!(%client, %attempt, %count, %message, ?on_panic, "Dispatcher panicked");
+                    if on_panic == PanicPolicy::Replace {
+                        match generate(&dispatcher_generator) {
+                            Ok(replacement) => dispatcher = replacement,
+                            Err(message) => warn!(
+                                %client,
+                                %message,
+                                "Dispatcher generator panicked, keeping the old dispatcher"
+                            ),
+                        }
+                    }
+                    (0..count)
+                        .map(|_| Attempt::Panicked(message.clone()))
+                        .collect()
+                }
+            };
+
+            let mut retry_inputs = retry_inputs.map(Vec::into_iter);
+            let mut retries = Vec::new();
+            for (mut output, result) in attempt_outputs.into_iter().zip(results) {
+                let retry_input = retry_inputs.as_mut().and_then(Iterator::next).flatten();
+                if policy.record_attempts {
+                    let latency_ns = end_ns - attempt_start_ns;
+                    output.core.attempt_latencies_ns.push(latency_ns);
+                }
+                let retryable = match &result {
+                    Attempt::Done(Err(error)) => policy.is_retryable(error),
+                    Attempt::TimedOut => policy.retry_timeouts,
+                    // panics aren't retried, the dispatcher may need replacing first
+                    Attempt::Done(Ok(_)) | Attempt::Panicked(_) => false,
+                };
+                match retry_input.filter(|_| retryable) {
+                    Some(input) => retries.push((output, input, result)),
+                    None => {
+                        // the policy allowed another attempt but there's nothing to make it with
+                        if retryable && retry_inputs.is_some() {
+                            counters.retries_skipped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        finished.push(result.record(output, end_ns));
+                    }
+                }
             }
-            Err(error) => {
-                output.error(error);
+            if retries.is_empty() {
+                break;
+            }
+
+            let wait = backoff.next(attempt);
+            trace!(%client, %attempt, retries = retries.len(), ?wait, "Retrying after backoff");
+            let cancelled = tokio::select! {
+                biased;
+                _ = shutdown.reached(ShutdownState::Cancelled) => true,
+                _ = tokio::time::sleep(wait) => false,
+            };
+            for (mut output, input, result) in retries {
+                if cancelled {
+                    // hand back the last attempt rather than cancelling it
+                    finished.push(result.record(output, end_ns));
+                } else {
+                    output.core.attempts += 1;
+                    pending.push((output, input));
+                }
             }
         }
-        all_outputs.push(output);
+        for _ in 0..size {
+            counters.finish_request();
+        }
+
+        for output in finished {
+            trace!(%client, iteration = output.core.iteration, "Client finished iteration");
+            // waits for space in the buffer if the sink is falling behind
+            if outputs.send(output).await.is_err() {
+                debug!(%client, "Output receiver closed");
+                break 'client;
+            }
+        }
+        if cancelled {
+            break;
+        }
 
-        iteration += 1;
-        trace!(%client, %iteration, "Client finished iteration");
+        let think_time = think_time.next();
+        if !think_time.is_zero() {
+            tokio::select! {
+                biased;
+                _ = shutdown.reached(ShutdownState::Cancelled) => break,
+                _ = tokio::time::sleep(think_time) => {}
+            }
+        }
+        idle.fetch_add(1, Ordering::Relaxed);
     }
 
     debug!(%client, "Client finished dispatching");
-    all_outputs
+}
+
+/// How an attempt at executing an input went.
+enum Attempt<O> {
+    Done(Result<O, String>),
+    TimedOut,
+    Panicked(String),
+}
+
+impl<O> Attempt<O> {
+    /// Record the attempt as the final one for the output.
+    fn record(self, mut output: Output<O>, end_ns: i64) -> Output<O> {
+        match self {
+            Attempt::Done(Ok(data)) => {
+                *output.data_mut() = data;
+                output.stop_at(end_ns);
+            }
+            Attempt::Done(Err(error)) => output.error_at(error, end_ns),
+            Attempt::TimedOut => {
+                debug!(
+                    client = output.core.client,
+                    iteration = output.core.iteration,
+                    "Request timed out"
+                );
+                output.timeout_at(end_ns);
+            }
+            Attempt::Panicked(message) => {
+                output.error_at(format!("dispatcher panicked: {message}"), end_ns)
+            }
+        }
+        output
+    }
+}
+
+/// Run the future, catching a panic from it rather than letting it take down the task.
+async fn catch_unwind<F: Future>(future: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    })
+    .await
+}
+
+/// Generate a dispatcher, or the panic message if the generator panics.
+///
+/// The generator is used again after a panic, as it was left.
+pub(crate) fn generate<G: DispatcherGenerator>(
+    generator: &Mutex<G>,
+) -> Result<G::Dispatcher, String> {
+    let mut generator = generator.lock().unwrap_or_else(PoisonError::into_inner);
+    std::panic::catch_unwind(AssertUnwindSafe(|| generator.generate()))
+        .map_err(|panic| panic_message(&*panic).to_owned())
+}
+
+/// The message that a panic was started with, if it was a string.
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Execute the inputs, as a batch if batching, returning `None` if it takes longer than the
+/// timeout.
+async fn execute<D: Dispatcher>(
+    dispatcher: &mut D,
+    inputs: Vec<D::Input>,
+    batching: bool,
+    timeout: Option<Duration>,
+) -> Option<Vec<Result<D::Output, String>>> {
+    let results = async {
+        if batching {
+            dispatcher.execute_batch(inputs).await
+        } else {
+            let mut results = Vec::with_capacity(inputs.len());
+            for input in inputs {
+                results.push(dispatcher.execute(input).await);
+            }
+            results
+        }
+    };
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, results).await.ok(),
+        None => Some(results.await),
+    }
+}
+
+/// The timeout for executing the inputs together, the longest of their timeouts so that none of
+/// them is given up on early, or `None` if any of them has no timeout.
+fn batch_timeout<D: Dispatcher>(
+    dispatcher: &D,
+    inputs: &[D::Input],
+    request_timeout: Option<Duration>,
+) -> Option<Duration> {
+    inputs
+        .iter()
+        .map(|input| dispatcher.timeout(input).or(request_timeout))
+        .try_fold(Duration::ZERO, |longest, timeout| {
+            Some(longest.max(timeout?))
+        })
 }
@@ -0,0 +1,68 @@
+//! Clocks give the timestamps recorded in outputs and reports.
+
+use std::fmt;
+
+use tokio::time::Instant;
+
+/// Gives the current time, in nanoseconds since the Unix epoch.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now_ns(&self) -> i64;
+}
+
+/// The system's wall clock.
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ns(&self) -> i64 {
+        chrono::Utc::now().timestamp_nanos()
+    }
+}
+
+/// Follows tokio's clock, counting from a fixed time.
+///
+/// With tokio's time paused, such as with `#[tokio::test(start_paused = true)]`, a run skips
+/// ahead through its waits instead of taking real time, and records the same timestamps every
+/// time it is run with the same seed.
+#[derive(Debug, Clone)]
+pub struct TokioClock {
+    epoch_ns: i64,
+    start: Instant,
+}
+
+impl TokioClock {
+    /// Start the clock at `epoch_ns` from tokio's current time.
+    pub fn new(epoch_ns: i64) -> Self {
+        Self {
+            epoch_ns,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Clock for TokioClock {
+    fn now_ns(&self) -> i64 {
+        self.epoch_ns + self.start.elapsed().as_nanos() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tokio_clock_follows_paused_time() {
+        let clock = TokioClock::new(1_000);
+        assert_eq!(clock.now_ns(), 1_000);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(clock.now_ns(), 10_000_001_000);
+    }
+}
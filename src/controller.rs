@@ -0,0 +1,210 @@
+//! Control of a run while it is going.
+
+use std::sync::{atomic::Ordering, Arc, Mutex, PoisonError};
+
+use tokio::sync::watch;
+
+use crate::{options::MAX_RATE, report::Counters};
+
+/// A handle for changing the load of a run while it is going and watching its progress.
+///
+/// Create one, pass a clone to the run with
+/// [`LoadOptionsBuilder::controller`](crate::LoadOptionsBuilder::controller) and keep the other
+/// to control it from elsewhere, such as from another task.
+/// Each run started with the controller gets fresh counters, while the rate and pause carry over
+/// to the next run.
+#[derive(Debug, Clone)]
+pub struct LoadController {
+    state: Arc<watch::Sender<ControlState>>,
+    /// The counters of the latest run, swapped for fresh ones when a run starts.
+    counters: Arc<Mutex<Arc<Counters>>>,
+}
+
+impl Default for LoadController {
+    fn default() -> Self {
+        let (state, _) = watch::channel(ControlState::default());
+        Self {
+            state: Arc::new(state),
+            counters: Arc::default(),
+        }
+    }
+}
+
+/// What the controller has asked of the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ControlState {
+    /// Rate overriding the load profile, if it has been set.
+    rate: Option<f64>,
+    paused: bool,
+    stopped: bool,
+}
+
+impl ControlState {
+    /// Rate overriding the load profile, zero while paused.
+    pub(crate) fn rate(&self) -> Option<f64> {
+        if self.paused {
+            Some(0.)
+        } else {
+            self.rate
+        }
+    }
+
+    pub(crate) fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub(crate) fn stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+/// Counters of a run, read while it is going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveCounters {
+    /// Inputs taken from the input generators so far.
+    pub inputs_generated: u64,
+    /// Inputs handed to a client so far.
+    pub inputs_sent: u64,
+    /// Inputs dropped by the overload policy so far.
+    pub inputs_dropped: u64,
+    /// Clients spawned so far.
+    pub clients: u32,
+    /// Requests executing right now.
+    pub in_flight: u32,
+}
+
+impl LoadController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send inputs at a constant rate from now on, in requests per second, instead of following
+    /// the load profile.
+    ///
+    /// The rate is split between shards like the profile would be. Negative rates are treated as
+    /// zero, as is NaN, and closed-loop runs ignore the rate.
+    pub fn set_rate(&self, rate: f64) {
+        let rate = if rate.is_nan() {
+            0.
+        } else {
+            rate.clamp(0., MAX_RATE)
+        };
+        self.state.send_modify(|state| state.rate = Some(rate));
+    }
+
+    /// Go back to following the load profile, from wherever the profile has got to.
+    pub fn follow_profile(&self) {
+        self.state.send_modify(|state| state.rate = None);
+    }
+
+    /// Stop sending inputs until resumed.
+    ///
+    /// The load profile carries on while paused, so an open-loop run picks up from wherever the
+    /// profile has got to, without catching up on the inputs it would have sent.
+    pub fn pause(&self) {
+        self.state.send_modify(|state| state.paused = true);
+    }
+
+    /// Start sending inputs again after a pause.
+    pub fn resume(&self) {
+        self.state.send_modify(|state| state.paused = false);
+    }
+
+    /// Stop generating load, letting in-flight requests finish and sending their outputs to the
+    /// sink like any other stop condition.
+    ///
+    /// The stop only lasts until the next run started with the controller, which runs as normal.
+    pub fn stop(&self) {
+        self.state.send_modify(|state| state.stopped = true);
+    }
+
+    /// The rate set with [`LoadController::set_rate`], or `None` if following the load profile.
+    pub fn rate(&self) -> Option<f64> {
+        self.state.borrow().rate
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.borrow().paused
+    }
+
+    /// The counters of the latest run so far.
+    pub fn counters(&self) -> LiveCounters {
+        let counters = self.current_counters();
+        LiveCounters {
+            inputs_generated: counters.generated.load(Ordering::Relaxed),
+            inputs_sent: counters.sent.load(Ordering::Relaxed),
+            inputs_dropped: counters.dropped.load(Ordering::Relaxed),
+            clients: counters.clients.load(Ordering::Relaxed),
+            in_flight: counters.in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Fresh counters for a run that is starting to update, which the controller reads from
+    /// then on.
+    ///
+    /// This also clears a stop from the last run.
+    pub(crate) fn start_run(&self) -> Arc<Counters> {
+        self.state
+            .send_if_modified(|state| std::mem::take(&mut state.stopped));
+        let counters = Arc::<Counters>::default();
+        *self.counters.lock().unwrap_or_else(PoisonError::into_inner) = Arc::clone(&counters);
+        counters
+    }
+
+    fn current_counters(&self) -> Arc<Counters> {
+        Arc::clone(&self.counters.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub(crate) fn watch(&self) -> ControlWatch {
+        ControlWatch(Some(self.state.subscribe()))
+    }
+}
+
+/// Watches a run's controller, never changing if it doesn't have one.
+pub(crate) struct ControlWatch(Option<watch::Receiver<ControlState>>);
+
+impl ControlWatch {
+    pub(crate) fn new(controller: Option<&LoadController>) -> Self {
+        controller.map_or(Self(None), LoadController::watch)
+    }
+
+    /// What the controller is currently asking for.
+    pub(crate) fn state(&self) -> ControlState {
+        self.0
+            .as_ref()
+            .map_or_else(ControlState::default, |receiver| *receiver.borrow())
+    }
+
+    /// Wait for the controller to ask for something new since the last change was seen.
+    pub(crate) async fn changed(&mut self) -> ControlState {
+        if let Some(receiver) = &mut self.0 {
+            if receiver.changed().await.is_ok() {
+                return *receiver.borrow_and_update();
+            }
+        }
+        // without a controller nothing will change
+        std::future::pending().await
+    }
+
+    /// Wait until the controller asks for the run to stop.
+    pub(crate) async fn stopped(&self) {
+        self.wait_for(ControlState::stopped).await;
+    }
+
+    /// Wait until the run isn't paused, or has been asked to stop.
+    pub(crate) async fn resumed(&self) {
+        self.wait_for(|state| !state.paused() || state.stopped())
+            .await;
+    }
+
+    /// Wait for a state matching `f`, without marking any changes as seen for
+    /// [`ControlWatch::changed`].
+    async fn wait_for(&self, f: impl FnMut(&ControlState) -> bool) -> ControlState {
+        if let Some(receiver) = &self.0 {
+            if let Ok(state) = receiver.clone().wait_for(f).await {
+                return *state;
+            }
+        }
+        std::future::pending().await
+    }
+}
@@ -1,5 +1,179 @@
+use std::{future::Future, pin::Pin};
+
+use async_trait::async_trait;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+mod combinators;
+
+pub use combinators::{Chain, Cycle, InputGeneratorExt, Interleave, Map, Mix, Take};
+
 pub trait InputGenerator {
     type Input: Send;
     fn next(&mut self) -> Option<Self::Input>;
     fn close(self);
+
+    /// The session that the input is part of, such as a user journey that needs the cookie
+    /// from its login, or `None` if it can go to any client.
+    ///
+    /// Every input of a session goes to the same client, which executes them in the order they
+    /// were generated.
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        None
+    }
+
+    /// Whether the input is the last of its session, releasing the session's client to be
+    /// assigned new sessions.
+    ///
+    /// A session that is never ended stays with its client for the rest of the run. Later inputs
+    /// with the same session ID start a new session, which may go to a different client.
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        false
+    }
+}
+
+/// An input generator that waits for its inputs, such as when reading them from a file, a socket
+/// or a database.
+///
+/// Every [`InputGenerator`] is also an `AsyncInputGenerator`, so the load generator takes either.
+/// The load generator waits for each input when it is due, so a slow source holds up the
+/// schedule, wrap it in a [`Prefetch`] to keep inputs ready ahead of time.
+#[async_trait]
+pub trait AsyncInputGenerator {
+    type Input: Send;
+    async fn next(&mut self) -> Option<Self::Input>;
+    async fn close(self);
+
+    /// The session that the input is part of, as with [`InputGenerator::session`].
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        None
+    }
+
+    /// Whether the input is the last of its session, as with [`InputGenerator::ends_session`].
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        false
+    }
+}
+
+// Written out rather than with `async_trait` so that the futures don't hold on to the generator,
+// which would need it to be `Send`.
+impl<G: InputGenerator> AsyncInputGenerator for G {
+    type Input = G::Input;
+
+    fn next<'life0, 'async_trait>(
+        &'life0 mut self,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Input>> + Send + 'async_trait>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(std::future::ready(InputGenerator::next(self)))
+    }
+
+    fn close<'async_trait>(self) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+    {
+        InputGenerator::close(self);
+        Box::pin(std::future::ready(()))
+    }
+
+    fn session(&self, input: &Self::Input) -> Option<u64> {
+        InputGenerator::session(self, input)
+    }
+
+    fn ends_session(&self, input: &Self::Input) -> bool {
+        InputGenerator::ends_session(self, input)
+    }
+}
+
+/// The session of an input, worked out along with it by generators that pass inputs on from
+/// another.
+#[derive(Debug, Clone, Copy, Default)]
+struct Session {
+    id: Option<u64>,
+    ends: bool,
+}
+
+impl Session {
+    fn of<G: AsyncInputGenerator>(generator: &G, input: &G::Input) -> Self {
+        Self {
+            id: generator.session(input),
+            ends: generator.ends_session(input),
+        }
+    }
+}
+
+/// Generates inputs ahead of time in a task of its own, keeping up to a buffer of them ready so
+/// that the cost of building them doesn't delay the schedule.
+///
+/// Generation starts straight away, so this needs to be made from within a tokio runtime.
+pub struct Prefetch<T> {
+    inputs: mpsc::Receiver<(T, Session)>,
+    task: JoinHandle<()>,
+    /// The session of the input that was taken last.
+    session: Session,
+}
+
+impl<T: Send + 'static> Prefetch<T> {
+    /// Start generating inputs from `generator`, keeping up to `buffer` of them ready.
+    ///
+    /// # Panics
+    ///
+    /// If `buffer` is zero or this is called outside of a tokio runtime.
+    pub fn new<G>(mut generator: G, buffer: usize) -> Self
+    where
+        G: AsyncInputGenerator<Input = T> + Send + 'static,
+    {
+        let (sender, inputs) = mpsc::channel(buffer);
+        let task = tokio::spawn(async move {
+            loop {
+                let input = tokio::select! {
+                    biased;
+                    _ = sender.closed() => break,
+                    input = generator.next() => input,
+                };
+                let Some(input) = input else {
+                    break;
+                };
+                let session = Session::of(&generator, &input);
+                if sender.send((input, session)).await.is_err() {
+                    break;
+                }
+            }
+            generator.close().await;
+        });
+        Self {
+            inputs,
+            task,
+            session: Session::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Send + 'static> AsyncInputGenerator for Prefetch<T> {
+    type Input = T;
+
+    async fn next(&mut self) -> Option<Self::Input> {
+        let (input, session) = self.inputs.recv().await?;
+        self.session = session;
+        Some(input)
+    }
+
+    /// Stop generating and wait for the generator to be closed.
+    async fn close(mut self) {
+        self.inputs.close();
+        // a panic in the generator has already been reported by the task
+        let _ = self.task.await;
+    }
+
+    /// The session of the input that was taken last, which the generator worked out along with
+    /// the input.
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        self.session.id
+    }
+
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        self.session.ends
+    }
 }
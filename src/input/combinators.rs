@@ -0,0 +1,561 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{InputGenerator, Session};
+
+/// Combinators for building input generators out of others.
+///
+/// Closing a combined generator closes every generator inside it, and each input keeps the
+/// session that its own generator gave it, along with whether it ends the session.
+///
+/// The combinators that [`Iterator`] has too are named after the inputs, so that they don't
+/// clash on iterators, which are input generators as well.
+pub trait InputGeneratorExt: InputGenerator + Sized {
+    /// Turn each input into another.
+    fn map_inputs<B: Send, F: FnMut(Self::Input) -> B>(self, f: F) -> Map<Self, F> {
+        Map {
+            inner: self,
+            f,
+            session: Session::default(),
+        }
+    }
+
+    /// Generate at most `n` inputs.
+    fn take_inputs(self, n: usize) -> Take<Self> {
+        Take {
+            inner: self,
+            remaining: n,
+        }
+    }
+
+    /// Generate the inputs of this generator, then those of `other`.
+    fn chain_inputs<G: InputGenerator<Input = Self::Input>>(self, other: G) -> Chain<Self, G> {
+        Chain {
+            first: Some(self),
+            second: other,
+            session: Session::default(),
+        }
+    }
+
+    /// Start again from a copy of this generator each time it runs out.
+    ///
+    /// Each spent copy is closed as the next one starts, as is the original when the cycle is
+    /// closed. A generator that starts out empty stays empty.
+    fn cycle_inputs(self) -> Cycle<Self>
+    where
+        Self: Clone,
+    {
+        Cycle {
+            current: Some(self.clone()),
+            original: self,
+            session: Session::default(),
+        }
+    }
+
+    /// Take inputs from this generator and `other` in turn, carrying on with whichever is left
+    /// once one runs out.
+    fn interleave<G: InputGenerator<Input = Self::Input>>(self, other: G) -> Interleave<Self, G> {
+        Interleave {
+            first: Some(self),
+            second: Some(other),
+            take_second: false,
+            session: Session::default(),
+        }
+    }
+
+    /// Pick each input at random from this generator or `other`, in proportion to their weights.
+    ///
+    /// More generators can be added to the mix with [`Mix::add`], and the picks are seeded with
+    /// 0 unless changed with [`Mix::seed`].
+    ///
+    /// # Panics
+    ///
+    /// If either weight is negative or not finite.
+    fn mix<G>(self, weight: f64, other: G, other_weight: f64) -> Mix<Self::Input>
+    where
+        Self: Send + 'static,
+        G: InputGenerator<Input = Self::Input> + Send + 'static,
+    {
+        Mix::new(0).add(weight, self).add(other_weight, other)
+    }
+}
+
+impl<G: InputGenerator> InputGeneratorExt for G {}
+
+/// Every iterator generates its items as inputs, such as `0..100`, with nothing to close.
+///
+/// With [`InputGenerator`] in scope, call `next` on an iterator as `Iterator::next(&mut iter)` or
+/// `InputGenerator::next(&mut iter)`, as either could be meant.
+impl<I: Iterator> InputGenerator for I
+where
+    I::Item: Send,
+{
+    type Input = I::Item;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        Iterator::next(self)
+    }
+
+    fn close(self) {}
+}
+
+/// Made by [`InputGeneratorExt::map_inputs`].
+#[derive(Clone)]
+pub struct Map<G, F> {
+    inner: G,
+    f: F,
+    /// Session of the input taken last, as the mapped input can't be asked for it.
+    session: Session,
+}
+
+impl<G: InputGenerator, B: Send, F: FnMut(G::Input) -> B> InputGenerator for Map<G, F> {
+    type Input = B;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        let input = self.inner.next()?;
+        self.session = Session::of(&self.inner, &input);
+        Some((self.f)(input))
+    }
+
+    fn close(self) {
+        self.inner.close();
+    }
+
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        self.session.id
+    }
+
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        self.session.ends
+    }
+}
+
+/// Made by [`InputGeneratorExt::take_inputs`].
+#[derive(Clone)]
+pub struct Take<G> {
+    inner: G,
+    remaining: usize,
+}
+
+impl<G: InputGenerator> InputGenerator for Take<G> {
+    type Input = G::Input;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        self.inner.next()
+    }
+
+    fn close(self) {
+        self.inner.close();
+    }
+
+    fn session(&self, input: &Self::Input) -> Option<u64> {
+        self.inner.session(input)
+    }
+
+    fn ends_session(&self, input: &Self::Input) -> bool {
+        self.inner.ends_session(input)
+    }
+}
+
+/// Made by [`InputGeneratorExt::chain_inputs`].
+#[derive(Clone)]
+pub struct Chain<A, B> {
+    /// The first generator, until it runs out and is closed.
+    first: Option<A>,
+    second: B,
+    session: Session,
+}
+
+impl<A: InputGenerator, B: InputGenerator<Input = A::Input>> InputGenerator for Chain<A, B> {
+    type Input = A::Input;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        if let Some(first) = &mut self.first {
+            if let Some(input) = first.next() {
+                self.session = Session::of(first, &input);
+                return Some(input);
+            }
+            if let Some(first) = self.first.take() {
+                first.close();
+            }
+        }
+        let input = self.second.next()?;
+        self.session = Session::of(&self.second, &input);
+        Some(input)
+    }
+
+    fn close(self) {
+        if let Some(first) = self.first {
+            first.close();
+        }
+        self.second.close();
+    }
+
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        self.session.id
+    }
+
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        self.session.ends
+    }
+}
+
+/// Made by [`InputGeneratorExt::cycle_inputs`].
+#[derive(Clone)]
+pub struct Cycle<G> {
+    original: G,
+    /// The copy being taken from, until it turns out that the generator starts out empty.
+    current: Option<G>,
+    session: Session,
+}
+
+impl<G: InputGenerator + Clone> InputGenerator for Cycle<G> {
+    type Input = G::Input;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        let current = self.current.as_mut()?;
+        let input = match current.next() {
+            Some(input) => input,
+            None => {
+                let spent = std::mem::replace(current, self.original.clone());
+                spent.close();
+                match current.next() {
+                    Some(input) => input,
+                    None => {
+                        if let Some(current) = self.current.take() {
+                            current.close();
+                        }
+                        return None;
+                    }
+                }
+            }
+        };
+        self.session = Session::of(self.current.as_ref()?, &input);
+        Some(input)
+    }
+
+    fn close(self) {
+        if let Some(current) = self.current {
+            current.close();
+        }
+        self.original.close();
+    }
+
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        self.session.id
+    }
+
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        self.session.ends
+    }
+}
+
+/// Made by [`InputGeneratorExt::interleave`].
+#[derive(Clone)]
+pub struct Interleave<A, B> {
+    /// Each generator, until it runs out and is closed.
+    first: Option<A>,
+    second: Option<B>,
+    take_second: bool,
+    session: Session,
+}
+
+impl<A: InputGenerator, B: InputGenerator<Input = A::Input>> InputGenerator for Interleave<A, B> {
+    type Input = A::Input;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        // try the one whose turn it is, then the other
+        for _ in 0..2 {
+            let take_second = self.take_second;
+            self.take_second = !take_second;
+            if take_second {
+                if let Some(second) = &mut self.second {
+                    if let Some(input) = second.next() {
+                        self.session = Session::of(second, &input);
+                        return Some(input);
+                    }
+                    if let Some(second) = self.second.take() {
+                        second.close();
+                    }
+                }
+            } else if let Some(first) = &mut self.first {
+                if let Some(input) = first.next() {
+                    self.session = Session::of(first, &input);
+                    return Some(input);
+                }
+                if let Some(first) = self.first.take() {
+                    first.close();
+                }
+            }
+        }
+        None
+    }
+
+    fn close(self) {
+        if let Some(first) = self.first {
+            first.close();
+        }
+        if let Some(second) = self.second {
+            second.close();
+        }
+    }
+
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        self.session.id
+    }
+
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        self.session.ends
+    }
+}
+
+/// An input generator of any type, so that different types can be mixed.
+trait BoxedInputGenerator<I>: Send {
+    fn next(&mut self) -> Option<I>;
+    fn close(self: Box<Self>);
+    fn session(&self, input: &I) -> Option<u64>;
+    fn ends_session(&self, input: &I) -> bool;
+}
+
+impl<G: InputGenerator + Send> BoxedInputGenerator<G::Input> for G {
+    fn next(&mut self) -> Option<G::Input> {
+        InputGenerator::next(self)
+    }
+
+    fn close(self: Box<Self>) {
+        InputGenerator::close(*self);
+    }
+
+    fn session(&self, input: &G::Input) -> Option<u64> {
+        InputGenerator::session(self, input)
+    }
+
+    fn ends_session(&self, input: &G::Input) -> bool {
+        InputGenerator::ends_session(self, input)
+    }
+}
+
+/// Picks each input at random from one of several generators, in proportion to their weights,
+/// such as 80% reads and 20% writes.
+///
+/// Generators that run out are closed and left out from then on, the mix runs out once all of
+/// them have.
+pub struct Mix<I> {
+    generators: Vec<(f64, Box<dyn BoxedInputGenerator<I>>)>,
+    rng: StdRng,
+    session: Session,
+}
+
+impl<I: Send + 'static> Mix<I> {
+    /// An empty mix, picking generators with randomness seeded by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            generators: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+            session: Session::default(),
+        }
+    }
+
+    /// Add a generator to the mix.
+    ///
+    /// # Panics
+    ///
+    /// If the weight is negative or not finite.
+    pub fn add<G>(mut self, weight: f64, generator: G) -> Self
+    where
+        G: InputGenerator<Input = I> + Send + 'static,
+    {
+        assert!(
+            weight.is_finite() && weight >= 0.,
+            "Mix weights must be finite and not negative, got {weight}"
+        );
+        self.generators.push((weight, Box::new(generator)));
+        self
+    }
+
+    /// Seed the randomness used to pick generators.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+}
+
+impl<I: Send> InputGenerator for Mix<I> {
+    type Input = I;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        loop {
+            let total: f64 = self.generators.iter().map(|(weight, _)| weight).sum();
+            if total <= 0. {
+                return None;
+            }
+            let mut pick = self.rng.gen::<f64>() * total;
+            // in case rounding leaves some over, which can't go to a generator without weight
+            let mut index = self
+                .generators
+                .iter()
+                .rposition(|(weight, _)| *weight > 0.)
+                .expect("Some generator has weight");
+            for (i, (weight, _)) in self.generators.iter().enumerate() {
+                if pick < *weight {
+                    index = i;
+                    break;
+                }
+                pick -= weight;
+            }
+            let generator = &mut self.generators[index].1;
+            if let Some(input) = generator.next() {
+                self.session = Session {
+                    id: generator.session(&input),
+                    ends: generator.ends_session(&input),
+                };
+                return Some(input);
+            }
+            let (_, spent) = self.generators.remove(index);
+            spent.close();
+        }
+    }
+
+    fn close(self) {
+        for (_, generator) in self.generators {
+            generator.close();
+        }
+    }
+
+    fn session(&self, _input: &Self::Input) -> Option<u64> {
+        self.session.id
+    }
+
+    fn ends_session(&self, _input: &Self::Input) -> bool {
+        self.session.ends
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    /// Counts up to a limit, counting how many times it has been closed.
+    #[derive(Clone)]
+    struct Counter {
+        next: u32,
+        end: u32,
+        closed: Arc<AtomicUsize>,
+    }
+
+    impl Counter {
+        fn new(start: u32, end: u32, closed: &Arc<AtomicUsize>) -> Self {
+            Self {
+                next: start,
+                end,
+                closed: Arc::clone(closed),
+            }
+        }
+    }
+
+    impl InputGenerator for Counter {
+        type Input = u32;
+
+        fn next(&mut self) -> Option<Self::Input> {
+            let next = self.next;
+            (next < self.end).then(|| {
+                self.next += 1;
+                next
+            })
+        }
+
+        fn close(self) {
+            self.closed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn session(&self, input: &Self::Input) -> Option<u64> {
+            Some(*input as u64 % 2)
+        }
+
+        fn ends_session(&self, input: &Self::Input) -> bool {
+            *input >= 2
+        }
+    }
+
+    fn collect<G: InputGenerator>(mut generator: G) -> Vec<(G::Input, Option<u64>)> {
+        let mut inputs = Vec::new();
+        while let Some(input) = generator.next() {
+            let session = generator.session(&input);
+            inputs.push((input, session));
+        }
+        generator.close();
+        inputs
+    }
+
+    fn inputs<I, S>(generated: Vec<(I, S)>) -> Vec<I> {
+        generated.into_iter().map(|(input, _)| input).collect()
+    }
+
+    #[test]
+    fn test_iter() {
+        let generated = collect((0..3).map(|i| i * 2).cycle_inputs().take_inputs(4));
+        assert_eq!(generated, [(0, None), (2, None), (4, None), (0, None)]);
+    }
+
+    #[test]
+    fn test_combinators() {
+        let closed = Arc::default();
+        let counter = |start, end| Counter::new(start, end, &closed);
+
+        // sessions come from the generator before mapping
+        let generated = collect(counter(0, 3).map_inputs(|i| i * 10));
+        assert_eq!(generated, [(0, Some(0)), (10, Some(1)), (20, Some(0))]);
+        let mut mapped = counter(0, 4)
+            .chain_inputs(counter(0, 0))
+            .map_inputs(|i| i * 10);
+        let mut ends = Vec::new();
+        while let Some(input) = InputGenerator::next(&mut mapped) {
+            ends.push(InputGenerator::ends_session(&mapped, &input));
+        }
+        assert_eq!(ends, [false, false, true, true]);
+        InputGenerator::close(mapped);
+        assert_eq!(inputs(collect(counter(0, 10).take_inputs(3))), [0, 1, 2]);
+        assert_eq!(
+            inputs(collect(counter(0, 2).chain_inputs(counter(5, 7)))),
+            [0, 1, 5, 6]
+        );
+        assert_eq!(
+            inputs(collect(counter(0, 2).cycle_inputs().take_inputs(5))),
+            [0, 1, 0, 1, 0]
+        );
+        assert_eq!(inputs(collect(counter(0, 0).cycle_inputs())), []);
+        assert_eq!(
+            inputs(collect(counter(0, 2).interleave(counter(10, 14)))),
+            [0, 10, 1, 11, 12, 13]
+        );
+        // every inner generator was closed, including the spent copies of the cycles
+        assert_eq!(closed.load(Ordering::Relaxed), 1 + 2 + 1 + 2 + 4 + 3 + 2);
+    }
+
+    #[test]
+    fn test_mix() {
+        let closed = Arc::default();
+        let mix = Counter::new(0, 1000, &closed)
+            .mix(3., Counter::new(1000, 2000, &closed), 1.)
+            .add(0., Counter::new(2000, 3000, &closed))
+            .seed(7)
+            .take_inputs(1000);
+        let generated = inputs(collect(mix));
+        let first = generated.iter().filter(|i| **i < 1000).count();
+        assert!((700..=800).contains(&first), "{first}");
+        assert!(generated.iter().all(|i| *i < 2000));
+        assert_eq!(closed.load(Ordering::Relaxed), 3);
+
+        // carries on with the rest as each runs out
+        let mix = Counter::new(0, 2, &closed).mix(1., Counter::new(10, 12, &closed), 1.);
+        let mut generated = inputs(collect(mix));
+        generated.sort();
+        assert_eq!(generated, [0, 1, 10, 11]);
+        assert_eq!(closed.load(Ordering::Relaxed), 5);
+    }
+}
@@ -1,9 +1,33 @@
+pub mod arrival;
 pub mod client;
+pub mod clock;
+mod controller;
 pub mod input;
 mod loadgen;
+pub mod mode;
+mod options;
 mod output;
 pub mod output_sink;
+pub mod profile;
+mod report;
+mod retry;
+mod scenario;
+mod schedule;
+mod session;
+mod shutdown;
+#[cfg(test)]
+mod testing;
 
-pub use loadgen::generate_load;
+pub use controller::{LiveCounters, LoadController};
+pub use loadgen::{generate_load, generate_load_sharded, StopReason};
+pub use options::{
+    LoadOptions, LoadOptionsBuilder, LoadOptionsError, MissedTickBehavior, OverloadPolicy, Pacing,
+    PanicPolicy, PhaseLength,
+};
+pub use output::Outcome;
 pub use output::Output;
 pub use output::OutputCore;
+pub use output::Phase;
+pub use report::{FailedJoin, Histogram, RunReport, ScenarioReport, ScheduleReport};
+pub use retry::RetryPolicy;
+pub use scenario::{generate_scenarios, Scenario};
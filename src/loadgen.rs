@@ -1,118 +1,2363 @@
-use serde::Serialize;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use async_channel::TrySendError;
-use tokio::time::interval;
-use tracing::{info, warn, trace, debug};
+use tokio::{
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, info, trace, warn};
 
 use crate::{
-    client::{self, Dispatcher, DispatcherGenerator},
-    input::InputGenerator,
+    client::{
+        self, ClientContext, ClientId, ClientInputs, Dispatcher, DispatcherGenerator, Request,
+    },
+    controller::ControlWatch,
+    // not imported, as its `next` would clash with that of every iterator
+    input,
+    mode::{LoadMode, ThinkTime},
+    options::{LoadOptions, LoadOptionsError, OverloadPolicy, PhaseLength},
+    output::{Output, Phase},
     output_sink::OutputSink,
+    report::{Counters, FailedJoin, RunReport, ScheduleReport},
+    retry::RetryPolicy,
+    schedule::{Pacer, Schedule},
+    session::Sessions,
+    shutdown::{Canceller, Shutdown, ShutdownState, ShutdownWatch},
 };
 
-/// Tries to generate a request every interval milliseconds for a total number of requests.
+/// How often to log progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The condition that ended a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// The total number of requests were generated.
+    Total,
+    /// The run reached its duration.
+    Duration,
+    /// The input generator ran out of inputs.
+    InputExhausted,
+    /// All of the clients went away.
+    ClientsClosed,
+    /// The process was sent SIGINT or SIGTERM.
+    Signal,
+    /// The run was stopped with its [`LoadController`](crate::LoadController).
+    Stopped,
+    /// The load profile settled on a rate of zero for good, in an open-loop run without a
+    /// duration or controller, so nothing more would have been sent.
+    ProfileEnded,
+    /// Every shard failed before it stopped, as listed in the report's failed joins.
+    Failed,
+}
+
+/// Generates load until one of the configured stop conditions is reached, sending the outputs to
+/// the sink as they complete.
+///
+/// Outputs pass through a buffer of the configured size on their way to the sink, so a sink that
+/// can't keep up holds up the clients instead of outputs building up in memory.
+///
+/// In open-loop mode it tries to generate requests following the configured load profile, spacing
+/// them out with the configured arrival process.
 /// If it would block trying to spawn the request it will create a new client.
+///
+/// In closed-loop mode the fixed set of clients each send their next request as soon as they are
+/// ready.
+///
+/// This runs a single generator loop, use [`generate_load_sharded`] to split the run into the
+/// configured number of shards.
+///
+/// The generator loop runs as a task of its own while the sink is fed on the calling task, so the
+/// input generator needs to be `Send + 'static`. The dispatcher generator is shared with the
+/// clients so that they can replace a dispatcher that panics, so it needs to be too.
+///
+/// Returns a [`RunReport`] describing the run from the generator's side. If the generator loop
+/// panics, the report lists it as a failed join rather than the panic carrying on to the caller.
+///
+/// Returns an error, without running, if the options have more than one shard.
 pub async fn generate_load<
-    D: DispatcherGenerator,
-    I: InputGenerator<Input = <D::Dispatcher as Dispatcher>::Input>,
+    D: DispatcherGenerator + Send + 'static,
+    I: input::AsyncInputGenerator<Input = <D::Dispatcher as Dispatcher>::Input> + Send + 'static,
     S: OutputSink<<D::Dispatcher as Dispatcher>::Output> + 'static,
 >(
-    // TODO: move to options struct
-    rate: u64,
-    initial_clients: u64,
-    total: u64,
-    max_clients: Option<u32>,
-    mut input_generator: I,
-    mut dispatcher_generator: D,
+    options: LoadOptions,
+    input_generator: I,
+    dispatcher_generator: D,
     output_sink: &mut S,
-) where
+) -> Result<RunReport, LoadOptionsError>
+where
     <D::Dispatcher as Dispatcher>::Output: Serialize + Default,
 {
-    let (input_sender, input_receiver) = async_channel::bounded(1);
+    if options.shards > 1 {
+        return Err(LoadOptionsError::ShardsNeedSharded(options.shards));
+    }
+    let (run, output_sender, output_receiver) = Run::new(options);
+    let shard = run.shard(0, 1, &output_sender);
+    drop(output_sender);
 
-    let nanos_in_second = 1_000_000_000;
-    let interval_nanos = nanos_in_second / rate;
+    // the generator loop runs as a task of its own, so a sink that blocks doesn't hold it up
+    let shard = tokio::spawn(run_shard(shard, input_generator, dispatcher_generator));
+    let sink = drain(output_receiver, output_sink, run.shutdown.watch());
+    let (report, ()) = tokio::join!(shard, sink);
+    let report = match report {
+        Ok(report) => run.report(vec![report], Vec::new()),
+        Err(error) => {
+            let error = join_error(error);
+            warn!(%error, "Failed to join shard");
+            run.report(Vec::new(), vec![FailedJoin::shard(0, error)])
+        }
+    };
+    Ok(report)
+}
 
-    let mut ticker = interval(Duration::from_nanos(interval_nanos));
+/// Generates load like [`generate_load`], split across the configured number of shards.
+///
+/// Each shard is an independent generator loop with its own clients and input channel, following
+/// its share of the load profile. The input generator and dispatcher generator for each shard are
+/// made by calling `input_generators` and `dispatcher_generators` with the shard's index.
+/// Shards run as tasks on the current runtime, or each on its own thread if
+/// [`shard_threads`](crate::LoadOptionsBuilder::shard_threads) is set.
+///
+/// Outputs from all of the shards go to the one sink and client IDs are unique across the shards.
+/// The stop reason in the report is from the first shard to stop. A shard that panics, or whose
+/// runtime or thread couldn't be created, is listed in the report's failed joins while the rest
+/// of the shards carry on.
+pub async fn generate_load_sharded<D, I, S, FI, FD>(
+    options: LoadOptions,
+    mut input_generators: FI,
+    mut dispatcher_generators: FD,
+    output_sink: &mut S,
+) -> RunReport
+where
+    D: DispatcherGenerator + Send + 'static,
+    I: input::AsyncInputGenerator<Input = <D::Dispatcher as Dispatcher>::Input> + Send + 'static,
+    S: OutputSink<<D::Dispatcher as Dispatcher>::Output> + 'static,
+    FI: FnMut(u32) -> I,
+    FD: FnMut(u32) -> D,
+    <D::Dispatcher as Dispatcher>::Output: Serialize + Default,
+{
+    let shards = options.shards;
+    let threads = options.shard_threads;
+    let (run, output_sender, output_receiver) = Run::new(options);
 
-    let mut client_counter = 0;
-    let mut i = 0;
+    // shards report back as they finish, so the first report is from the first to stop
+    let (report_sender, mut report_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut handles = Vec::with_capacity(shards as usize);
+    for index in 0..shards {
+        let shard = run.shard(index, shards, &output_sender);
+        let input_generator = input_generators(index);
+        let dispatcher_generator = dispatcher_generators(index);
+        let report_sender = report_sender.clone();
+        let future = async move {
+            let report = run_shard(shard, input_generator, dispatcher_generator).await;
+            let _ = report_sender.send(report);
+        };
+        let handle = if threads {
+            spawn_shard_thread(index, future)
+        } else {
+            Ok(ShardHandle::Task(tokio::spawn(future)))
+        };
+        handles.push(handle);
+    }
+    drop(report_sender);
+    drop(output_sender);
+
+    let reports = async {
+        let mut reports = Vec::new();
+        while let Some(report) = report_receiver.recv().await {
+            reports.push(report);
+        }
+        reports
+    };
+    let sink = drain(output_receiver, output_sink, run.shutdown.watch());
+    let (reports, ()) = tokio::join!(reports, sink);
+
+    let mut failed_joins = Vec::new();
+    for (index, handle) in (0..shards).zip(handles) {
+        let result = match handle {
+            Ok(handle) => handle.join().await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            warn!(%error, shard = index, "Failed to join shard");
+            failed_joins.push(FailedJoin::shard(index, error));
+        }
+    }
+    run.report(reports, failed_joins)
+}
 
-    let mut tasks = Vec::with_capacity(initial_clients as usize);
+/// A shard running as a task on the current runtime or on a thread of its own.
+enum ShardHandle {
+    Task(JoinHandle<()>),
+    Thread(std::thread::JoinHandle<()>),
+}
 
-    for _ in 0..initial_clients {
-        client_counter += 1;
-        let receiver = input_receiver.clone();
+impl ShardHandle {
+    /// Wait for the shard to finish, returning why if it didn't.
+    async fn join(self) -> Result<(), String> {
+        match self {
+            Self::Task(task) => task.await.map_err(join_error),
+            // the thread has dropped its end of the channels by now, so it's about done
+            Self::Thread(thread) => {
+                match tokio::task::spawn_blocking(move || thread.join()).await {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(panic)) => Err(client::panic_message(&*panic).to_string()),
+                    Err(error) => Err(error.to_string()),
+                }
+            }
+        }
+    }
+}
 
-        let dispatcher = dispatcher_generator.generate();
-        let task =
-            tokio::spawn(async move { client::run(receiver, client_counter, dispatcher).await });
-        tasks.push(task);
+/// Why a task couldn't be joined, with the message of its panic if it panicked.
+fn join_error(error: tokio::task::JoinError) -> String {
+    match error.try_into_panic() {
+        Ok(panic) => client::panic_message(&*panic).to_string(),
+        Err(error) => error.to_string(),
     }
+}
 
-    while let Some(input) = input_generator.next() {
-        if i % rate == 0 {
-            info!(done = i, total = total, "Progressing");
+/// Run a shard on a thread of its own, with a runtime of its own.
+fn spawn_shard_thread(
+    index: u32,
+    future: impl std::future::Future<Output = ()> + Send + 'static,
+) -> Result<ShardHandle, String> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|error| format!("Failed to build runtime for shard: {error}"))?;
+    let thread = std::thread::Builder::new()
+        .name(format!("loadbench-shard-{index}"))
+        .spawn(move || runtime.block_on(future))
+        .map_err(|error| format!("Failed to spawn thread for shard: {error}"))?;
+    Ok(ShardHandle::Thread(thread))
+}
+
+/// What the shards of a run share.
+struct Run {
+    shutdown: Shutdown,
+    counters: Arc<Counters>,
+    options: LoadOptions,
+    seed: u64,
+    start: Instant,
+    start_ns: i64,
+}
+
+impl Run {
+    /// Set up a run, along with the channel that outputs are sent to the sink through.
+    fn new<O>(
+        options: LoadOptions,
+    ) -> (
+        Self,
+        async_channel::Sender<Output<O>>,
+        async_channel::Receiver<Output<O>>,
+    ) {
+        let (output_sender, output_receiver) = async_channel::bounded(options.output_buffer);
+        let seed = options.seed.unwrap_or_else(rand::random);
+        info!(%seed, mode = ?options.mode, shards = options.shards, "Starting load generation");
+        if options.total.is_none()
+            && options.duration.is_none()
+            && options.controller.is_none()
+            && !options.handle_signals
+        {
+            warn!("The run has no total, duration, controller or signals to stop it, it only stops when the input generator runs out");
+        }
+        // a controller reads the counters while the run is going
+        let counters = match &options.controller {
+            Some(controller) => controller.start_run(),
+            None => Arc::default(),
+        };
+        let start_ns = options.clock.now_ns();
+        let run = Self {
+            shutdown: Shutdown::new(options.handle_signals),
+            counters,
+            options,
+            seed,
+            start: Instant::now(),
+            start_ns,
+        };
+        (run, output_sender, output_receiver)
+    }
+
+    /// Everything one of `count` shards needs to run.
+    fn shard<O>(
+        &self,
+        index: u32,
+        count: u32,
+        outputs: &async_channel::Sender<Output<O>>,
+    ) -> Shard<O> {
+        Shard {
+            index,
+            count,
+            options: self.options.shard(index, count),
+            // the first shard keeps the seed so a single shard run matches an unsharded one
+            seed: self.seed.wrapping_add(index as u64),
+            start: self.start,
+            start_ns: self.start_ns,
+            shutdown: self.shutdown.watch(),
+            canceller: self.shutdown.canceller(),
+            context: ClientContext {
+                shutdown: self.shutdown.watch(),
+                outputs: outputs.clone(),
+                request_timeout: self.options.request_timeout,
+                counters: Arc::clone(&self.counters),
+                idle: Arc::default(),
+                clock: Arc::clone(&self.options.clock),
+                on_panic: self.options.on_panic,
+                batch_size: self.options.batch_size,
+                batch_wait: self.options.batch_wait,
+            },
+        }
+    }
+
+    /// Combine the reports of the shards, in the order that they stopped, along with the shards
+    /// that failed.
+    fn report(self, shards: Vec<ShardReport>, mut failed_joins: Vec<FailedJoin>) -> RunReport {
+        let stop_reason = shards
+            .first()
+            .map_or(StopReason::Failed, |first| first.stop_reason);
+        let mut schedule: Option<ScheduleReport> = None;
+        for shard in shards {
+            match (&mut schedule, shard.schedule) {
+                (Some(schedule), Some(other)) => schedule.merge(&other),
+                (None, other) => schedule = other,
+                (Some(_), None) => {}
+            }
+            failed_joins.extend(shard.failed_joins);
+        }
+        let counters = &self.counters;
+        let report = RunReport {
+            inputs_generated: counters.generated.load(Ordering::Relaxed),
+            inputs_sent: counters.sent.load(Ordering::Relaxed),
+            inputs_dropped: counters.dropped.load(Ordering::Relaxed),
+            clients_spawned: counters.clients.load(Ordering::Relaxed),
+            peak_in_flight: counters.peak_in_flight.load(Ordering::Relaxed),
+            retries_skipped: counters.retries_skipped.load(Ordering::Relaxed),
+            start_ns: self.start_ns,
+            end_ns: self.options.clock.now_ns(),
+            stop_reason,
+            schedule,
+            failed_joins,
+        };
+        info!(?report, "Finished run");
+        report
+    }
+}
+
+/// Send outputs to the sink as they arrive, until all of the clients have finished.
+async fn drain<O, S: OutputSink<O>>(
+    receiver: async_channel::Receiver<Output<O>>,
+    output_sink: &mut S,
+    mut shutdown: ShutdownWatch,
+) {
+    loop {
+        let output = tokio::select! {
+            biased;
+            _ = shutdown.reached(ShutdownState::Aborted) => break,
+            output = receiver.recv() => output,
+        };
+        match output {
+            Ok(output) => output_sink.send(output).await,
+            // all of the clients have finished
+            Err(_) => break,
+        }
+    }
+}
+
+/// One of the independent generator and client groups of a run.
+struct Shard<O> {
+    index: u32,
+    count: u32,
+    /// The options with this shard's share of the totals and clients.
+    options: LoadOptions,
+    seed: u64,
+    start: Instant,
+    start_ns: i64,
+    shutdown: ShutdownWatch,
+    canceller: Canceller,
+    context: ClientContext<O>,
+}
+
+/// How a shard's part of the run went.
+struct ShardReport {
+    stop_reason: StopReason,
+    schedule: Option<ScheduleReport>,
+    failed_joins: Vec<FailedJoin>,
+}
+
+/// Generate the shard's load, then wait for its clients to finish.
+async fn run_shard<
+    D: DispatcherGenerator + Send + 'static,
+    I: input::AsyncInputGenerator<Input = InputOf<D>>,
+>(
+    shard: Shard<OutputOf<D>>,
+    mut input_generator: I,
+    dispatcher_generator: D,
+) -> ShardReport
+where
+    <D::Dispatcher as Dispatcher>::Output: Serialize + Default,
+{
+    let Shard {
+        index,
+        count,
+        options,
+        seed,
+        start,
+        start_ns,
+        mut shutdown,
+        canceller,
+        context,
+    } = shard;
+    let LoadOptions {
+        mode,
+        profile,
+        initial_clients,
+        total,
+        duration,
+        max_clients,
+        arrival,
+        warmup,
+        cooldown,
+        shutdown_timeout,
+        overload,
+        missed_ticks,
+        pacing,
+        controller,
+        retry,
+        client_concurrency,
+        ..
+    } = options;
+    let mut control = ControlWatch::new(controller.as_ref());
+
+    // queued inputs wait in the channel for a client to become free
+    let input_capacity = match overload {
+        OverloadPolicy::Queue { depth } => depth,
+        OverloadPolicy::Block | OverloadPolicy::Drop => 1,
+    };
+    let (input_sender, input_receiver) = async_channel::bounded(input_capacity);
+
+    let (client_count, think_time) = match &mode {
+        LoadMode::Open => (initial_clients, ThinkTime::None),
+        LoadMode::Closed {
+            clients,
+            think_time,
+        } => (*clients, think_time.clone()),
+    };
+    let counters = Arc::clone(&context.counters);
+    let mut clients = Clients {
+        shard: index,
+        receiver: input_receiver,
+        dispatcher_generator: Arc::new(Mutex::new(dispatcher_generator)),
+        think_time,
+        retry,
+        seed,
+        max_clients,
+        overload,
+        context,
+        concurrency: client_concurrency,
+        count: 0,
+        tasks: Vec::new(),
+        live: Arc::default(),
+        sessions: Sessions::new(input_capacity),
+        failed_spawns: Vec::new(),
+    };
+    for _ in 0..client_count {
+        clients.spawn();
+    }
+    if client_count > 0 {
+        clients.close_if_none_live();
+    }
+
+    let bounds = Bounds {
+        start,
+        start_ns,
+        total,
+        // a duration too long to represent never ends the run
+        end: duration.and_then(|duration| start.checked_add(duration)),
+        warmup,
+        cooldown,
+    };
+
+    let (stop_reason, schedule) = match mode {
+        LoadMode::Open => {
+            let schedule = Schedule::new(profile, arrival.build(seed), start).shard(index, count);
+            let mut pacer = Pacer::new(schedule, missed_ticks, pacing);
+            // a controller can still set a rate after the profile has ended
+            if controller.is_none() {
+                pacer = pacer.stop_at_profile_end();
+            }
+            let (stop_reason, schedule_report) = open_loop(
+                pacer,
+                &bounds,
+                &mut input_generator,
+                &input_sender,
+                &mut clients,
+                &mut shutdown,
+                &mut control,
+            )
+            .await;
+            info!(
+                shard = index,
+                target_rate = schedule_report.target_rate,
+                achieved_rate = schedule_report.achieved_rate,
+                p99_lag = ?schedule_report.lag.percentile(0.99),
+                missed_ticks = schedule_report.missed_ticks,
+                "Kept to schedule"
+            );
+            (stop_reason, Some(schedule_report))
+        }
+        LoadMode::Closed { .. } => {
+            let stop_reason = closed_loop(
+                &bounds,
+                &mut input_generator,
+                &input_sender,
+                &mut clients.sessions,
+                &counters,
+                &mut shutdown,
+                &control,
+            )
+            .await;
+            (stop_reason, None)
+        }
+    };
+    info!(shard = index, ?stop_reason, "Stopped generating load");
+
+    info!("Closing load sender");
+    input_sender.close();
+    clients.sessions.close();
+    info!("Closing input generator");
+    input_generator.close().await;
+
+    // after a signal the in-flight requests only get until the timeout to finish, or as long as
+    // they take if the timeout is too long to represent
+    let mut cancel_at = match stop_reason {
+        StopReason::Signal => Instant::now().checked_add(shutdown_timeout),
+        _ => None,
+    };
+
+    // dropping the rest of the clients lets the sink see when the last one has finished
+    let Clients {
+        count: client_count,
+        tasks,
+        failed_spawns: mut failed_joins,
+        ..
+    } = clients;
+    let task_count = tasks.len();
+    let mut tasks = tasks.into_iter().enumerate();
+    while let Some((i, (client, mut task))) = tasks.next() {
+        debug!(task = i, total = task_count, "Waiting for task to finish");
+        let result = loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.reached(ShutdownState::Aborted) => {
+                    task.abort();
+                    for (_, (_, task)) in tasks {
+                        task.abort();
+                    }
+                    warn!("Aborted without sending the remaining outputs");
+                    return ShardReport { stop_reason, schedule, failed_joins };
+                }
+                _ = sleep_until_some(cancel_at) => {
+                    warn!("Timed out waiting for in-flight requests, cancelling them");
+                    canceller.cancel();
+                    cancel_at = None;
+                }
+                result = &mut task => break result,
+            }
+        };
+        if let Err(error) = result {
+            warn!(%error, %client, "Failed to join task");
+            failed_joins.push(FailedJoin {
+                shard: index,
+                client: Some(client),
+                error: error.to_string(),
+            });
         }
-        match input_sender.try_send(input) {
+    }
+
+    info!(shard = index, clients = %client_count, "Finished generating load");
+    ShardReport {
+        stop_reason,
+        schedule,
+        failed_joins,
+    }
+}
+
+/// When a run stops and how it is split into phases.
+struct Bounds {
+    start: Instant,
+    /// The start in wall clock time, for stamping inputs with when they were scheduled.
+    start_ns: i64,
+    total: Option<u64>,
+    end: Option<Instant>,
+    warmup: Option<PhaseLength>,
+    cooldown: Option<PhaseLength>,
+}
+
+impl Bounds {
+    /// Whether the run has generated its total number of requests.
+    fn reached_total(&self, generated: u64) -> bool {
+        self.total.is_some_and(|total| generated >= total)
+    }
+
+    /// Whether the run has reached the end of its duration.
+    fn reached_end(&self, at: Instant) -> bool {
+        self.end.is_some_and(|end| at >= end)
+    }
+
+    /// The phase of the run that the request with the given index, due at `at`, is part of.
+    fn phase(&self, index: u64, at: Instant) -> Phase {
+        let in_warmup = match self.warmup {
+            Some(PhaseLength::Requests(requests)) => index < requests,
+            Some(PhaseLength::Duration(duration)) => match self.start.checked_add(duration) {
+                Some(end) => at < end,
+                // a warmup too long to represent lasts the whole run
+                None => true,
+            },
+            None => false,
+        };
+        let in_cooldown = match (self.cooldown, self.total, self.end) {
+            (Some(PhaseLength::Requests(requests)), Some(total), _) => {
+                index >= total.saturating_sub(requests)
+            }
+            (Some(PhaseLength::Duration(duration)), _, Some(end)) => {
+                at >= end.checked_sub(duration).unwrap_or(self.start)
+            }
+            _ => false,
+        };
+        if in_warmup {
+            Phase::Warmup
+        } else if in_cooldown {
+            Phase::Cooldown
+        } else {
+            Phase::Measure
+        }
+    }
+}
+
+type InputOf<D> = <<D as DispatcherGenerator>::Dispatcher as Dispatcher>::Input;
+type OutputOf<D> = <<D as DispatcherGenerator>::Dispatcher as Dispatcher>::Output;
+
+/// The clients running for a load generation run.
+struct Clients<D: DispatcherGenerator> {
+    shard: u32,
+    receiver: async_channel::Receiver<Request<InputOf<D>>>,
+    /// Shared with the clients so that they can replace a dispatcher that panics.
+    dispatcher_generator: Arc<Mutex<D>>,
+    think_time: ThinkTime,
+    retry: RetryPolicy,
+    seed: u64,
+    max_clients: Option<u32>,
+    overload: OverloadPolicy,
+    context: ClientContext<OutputOf<D>>,
+    /// Most requests that each client executes at once, lowered to 1 if the dispatchers don't
+    /// hand out concurrent handles.
+    concurrency: u32,
+    /// Clients spawned so far.
+    count: u32,
+    /// The tasks running each client, along with its ID.
+    tasks: Vec<(u32, JoinHandle<()>)>,
+    /// Tasks that are still running, closing the receiver once they have all gone.
+    live: Arc<AtomicU32>,
+    /// Queues of session inputs for each of the tasks.
+    sessions: Sessions<InputOf<D>>,
+    /// Clients that couldn't be started as their dispatcher generator panicked.
+    failed_spawns: Vec<FailedJoin>,
+}
+
+impl<D: DispatcherGenerator + Send + 'static> Clients<D>
+where
+    <D::Dispatcher as Dispatcher>::Output: Serialize + Default,
+{
+    /// Whether another client can be spawned without going over the maximum.
+    fn can_spawn(&self) -> bool {
+        match self.max_clients {
+            Some(max_clients) => self.count < max_clients,
+            None => true,
+        }
+    }
+
+    /// Spawn a new client with a freshly generated dispatcher, running a task for each request it
+    /// can execute at once.
+    ///
+    /// A client whose dispatcher generator panics is recorded as failed instead, returning false.
+    fn spawn(&mut self) -> bool {
+        // IDs come from the run's counters so that they are unique across shards
+        let counters = &self.context.counters;
+        let id = counters.clients.fetch_add(1, Ordering::Relaxed) + 1;
+        self.count += 1;
+        // a client can't run without a dispatcher
+        let dispatcher = match client::generate(&self.dispatcher_generator) {
+            Ok(dispatcher) => dispatcher,
+            Err(message) => {
+                warn!(client = id, %message, "Dispatcher generator panicked, not starting client");
+                self.failed_spawns.push(FailedJoin {
+                    shard: self.shard,
+                    client: Some(id),
+                    error: format!("dispatcher generator panicked: {message}"),
+                });
+                return false;
+            }
+        };
+        let mut dispatchers = Vec::with_capacity(self.concurrency as usize);
+        for _ in 1..self.concurrency {
+            match dispatcher.concurrent_handle() {
+                Some(handle) => dispatchers.push(handle),
+                None => {
+                    warn!(
+                        concurrency = self.concurrency,
+                        "Dispatcher has no concurrent handles, running one request per client"
+                    );
+                    self.concurrency = 1;
+                    dispatchers.clear();
+                    break;
+                }
+            }
+        }
+        dispatchers.insert(0, dispatcher);
+
+        let client = ClientId {
+            id,
+            iterations: Arc::default(),
+        };
+        for (lane, dispatcher) in dispatchers.into_iter().enumerate() {
+            let inputs = ClientInputs {
+                shared: self.receiver.clone(),
+                sessions: self.sessions.add_task(),
+            };
+            let dispatcher_generator = Arc::clone(&self.dispatcher_generator);
+            // each task gets its own stream of think times
+            let seed = self
+                .seed
+                .wrapping_add(id as u64)
+                .wrapping_add((lane as u64) << 32);
+            let think_time = self.think_time.sampler(seed);
+            // and of backoff jitter, separate from the think times
+            let backoff = self.retry.backoff_sampler(!seed);
+            let client = client.clone();
+            let context = self.context.clone();
+            let live = LiveTask::new(&self.live, &self.receiver);
+            let task = tokio::spawn(async move {
+                let _live = live;
+                client::run(
+                    inputs,
+                    client,
+                    dispatcher,
+                    dispatcher_generator,
+                    think_time,
+                    backoff,
+                    context,
+                )
+                .await
+            });
+            self.tasks.push((id, task));
+            self.context.idle.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Close the input channel if no clients are running to take inputs from it, such as after
+    /// failing to start any, so that the generator stops rather than waiting for them.
+    fn close_if_none_live(&self) {
+        if self.live.load(Ordering::Relaxed) == 0 {
+            self.receiver.close();
+        }
+    }
+}
+
+/// Marks a client task as running, closing the shard's input channel once the last one has gone,
+/// however it ended.
+///
+/// Otherwise the generator would wait forever to hand an input to clients that have all panicked.
+struct LiveTask<I> {
+    live: Arc<AtomicU32>,
+    inputs: async_channel::Receiver<Request<I>>,
+}
+
+impl<I> LiveTask<I> {
+    fn new(live: &Arc<AtomicU32>, inputs: &async_channel::Receiver<Request<I>>) -> Self {
+        live.fetch_add(1, Ordering::Relaxed);
+        Self {
+            live: Arc::clone(live),
+            inputs: inputs.clone(),
+        }
+    }
+}
+
+impl<I> Drop for LiveTask<I> {
+    fn drop(&mut self) {
+        if self.live.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.inputs.close();
+        }
+    }
+}
+
+/// Send inputs as they become due on the schedule, spawning new clients when none are free.
+async fn open_loop<
+    D: DispatcherGenerator + Send + 'static,
+    I: input::AsyncInputGenerator<Input = InputOf<D>>,
+>(
+    mut pacer: Pacer,
+    bounds: &Bounds,
+    input_generator: &mut I,
+    input_sender: &async_channel::Sender<Request<I::Input>>,
+    clients: &mut Clients<D>,
+    shutdown: &mut ShutdownWatch,
+    control: &mut ControlWatch,
+) -> (StopReason, ScheduleReport)
+where
+    <D::Dispatcher as Dispatcher>::Output: Serialize + Default,
+{
+    // Inputs are stamped with the time they were scheduled for, in wall clock time, so that
+    // latency can be measured from when the request should have been sent.
+    let start_instant = pacer.start();
+    let start_ns = bounds.start_ns;
+    let mut last_progress = start_instant;
+    let mut i = 0;
+    let counters = Arc::clone(&clients.context.counters);
+
+    // the controller may have been used before the run started
+    let state = control.state();
+    if state.rate().is_some() {
+        pacer.set_rate(state.rate());
+    }
+
+    let stop_reason = loop {
+        if control.state().stopped() {
+            break StopReason::Stopped;
+        }
+        let next = tokio::select! {
+            biased;
+            _ = shutdown.reached(ShutdownState::Draining) => break StopReason::Signal,
+            state = control.changed() => {
+                // the input being waited for was planned for the old rate, so start afresh
+                pacer.set_rate(state.rate());
+                continue;
+            }
+            next = pacer.wait_for_next(bounds.end) => next,
+        };
+        let Some(scheduled) = next else {
+            if bounds.end.is_none() && pacer.profile_ended() {
+                break StopReason::ProfileEnded;
+            }
+            break StopReason::Duration;
+        };
+
+        // a slow input generator holds up the schedule, unless it is prefetching
+        let input = tokio::select! {
+            biased;
+            _ = shutdown.reached(ShutdownState::Draining) => break StopReason::Signal,
+            _ = control.stopped() => break StopReason::Stopped,
+            input = input_generator.next() => input,
+        };
+        let Some(input) = input else {
+            break StopReason::InputExhausted;
+        };
+        counters.generated.fetch_add(1, Ordering::Relaxed);
+        let session = input_generator.session(&input);
+        let ends_session = input_generator.ends_session(&input);
+        let request = Request {
+            input,
+            scheduled_ns: Some(start_ns + (scheduled - start_instant).as_nanos() as i64),
+            phase: bounds.phase(i, scheduled),
+            session,
+        };
+
+        if scheduled - last_progress >= PROGRESS_INTERVAL {
+            last_progress = scheduled;
+            info!(done = i, total = ?bounds.total, "Progressing");
+        }
+        // make a new client if there aren't enough free ones for this input and any still
+        // waiting, inputs of a session that has already started wait for its client
+        let waiting = match session {
+            None => input_sender.len() as u32 + 1,
+            Some(session) => (!clients.sessions.is_assigned(session)).into(),
+        };
+        let mut spawned = false;
+        let idle = clients.context.idle.load(Ordering::Relaxed);
+        if (idle < waiting || !clients.sessions.has_tasks()) && clients.can_spawn() {
+            spawned = clients.spawn();
+            if !spawned {
+                clients.close_if_none_live();
+            }
+        }
+        let sender = match session {
+            None => input_sender.clone(),
+            Some(session) => clients.sessions.queue(session).clone(),
+        };
+        match sender.try_send(request) {
             Ok(()) => {
-                // sent successfully, there must have been an available client
+                // sent successfully, there must have been an available client or space in the
+                // queue
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+                pacer.record_send(scheduled, Instant::now());
             }
             // TODO: maybe preallocate clients, or always keep a few spare
-            Err(TrySendError::Full(input)) => {
-                // wasn't available so create a new client to service the request
-                let generate_new_client = if let Some(max_clients) = max_clients {
-                    client_counter < max_clients
+            Err(TrySendError::Full(request)) => {
+                // a free client will take the waiting input shortly, otherwise the clients are
+                // saturated, but a session's input always waits for its client, as dropping it
+                // would leave the rest of the journey to run without it
+                let saturated = session.is_none()
+                    && !spawned
+                    && clients.context.idle.load(Ordering::Relaxed) == 0;
+                if saturated && clients.overload != OverloadPolicy::Block {
+                    trace!(index = i, "Dropping input, all clients are busy");
+                    let now_ns = clients.context.clock.now_ns();
+                    let mut output = Output::start_at(0, 0, request.scheduled_ns, now_ns);
+                    output.core.phase = request.phase;
+                    output.core.session = request.session;
+                    output.dropped();
+                    counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    let sent = tokio::select! {
+                        biased;
+                        _ = shutdown.reached(ShutdownState::Draining) => break StopReason::Signal,
+                        sent = clients.context.outputs.send(output) => sent,
+                    };
+                    if sent.is_err() {
+                        warn!("Output receiver closed while recording a dropped input");
+                    }
                 } else {
-                    true
-                };
-                if generate_new_client {
-                    client_counter += 1;
-                    let receiver = input_receiver.clone();
-                    let dispatcher = dispatcher_generator.generate();
-                    let task = tokio::spawn(async move {
-                        client::run(receiver, client_counter, dispatcher).await
-                    });
-                    tasks.push(task);
+                    let sent = tokio::select! {
+                        biased;
+                        _ = shutdown.reached(ShutdownState::Draining) => break StopReason::Signal,
+                        _ = control.stopped() => break StopReason::Stopped,
+                        sent = sender.send(request) => sent,
+                    };
+                    // the channel closes if the clients all die while this waits for them
+                    if sent.is_err() {
+                        warn!("Input sender already closed while trying to generate more load");
+                        break StopReason::ClientsClosed;
+                    }
+                    counters.sent.fetch_add(1, Ordering::Relaxed);
+                    pacer.record_send(scheduled, Instant::now());
                 }
-                input_sender.send(input).await.unwrap();
             }
             Err(TrySendError::Closed(_value)) => {
                 // nothing else to do but stop the loop
                 warn!("Input sender already closed while trying to generate more load");
-                break;
+                break StopReason::ClientsClosed;
             }
         }
+        if let Some(session) = session.filter(|_| ends_session) {
+            clients.sessions.end(session);
+        }
+
+        i += 1;
+        if bounds.reached_total(i) {
+            break StopReason::Total;
+        }
+    };
 
-        ticker.tick().await;
+    (stop_reason, pacer.report())
+}
+
+/// Hand inputs to the fixed set of clients as quickly as they will take them.
+///
+/// An input of a session waits for its session's client to take it, holding up the inputs after
+/// it.
+async fn closed_loop<I: input::AsyncInputGenerator>(
+    bounds: &Bounds,
+    input_generator: &mut I,
+    input_sender: &async_channel::Sender<Request<I::Input>>,
+    sessions: &mut Sessions<I::Input>,
+    counters: &Counters,
+    shutdown: &mut ShutdownWatch,
+    control: &ControlWatch,
+) -> StopReason {
+    let mut last_progress = Instant::now();
+    let mut i = 0;
+
+    loop {
+        if control.state().paused() {
+            tokio::select! {
+                biased;
+                _ = shutdown.reached(ShutdownState::Draining) => break StopReason::Signal,
+                _ = sleep_until_some(bounds.end) => break StopReason::Duration,
+                _ = control.resumed() => {}
+            }
+        }
+        if control.state().stopped() {
+            break StopReason::Stopped;
+        }
+        let now = Instant::now();
+        if bounds.reached_end(now) {
+            break StopReason::Duration;
+        }
+        let input = tokio::select! {
+            biased;
+            _ = shutdown.reached(ShutdownState::Draining) => break StopReason::Signal,
+            _ = control.stopped() => break StopReason::Stopped,
+            input = input_generator.next() => input,
+        };
+        let Some(input) = input else {
+            break StopReason::InputExhausted;
+        };
+        counters.generated.fetch_add(1, Ordering::Relaxed);
+        let session = input_generator.session(&input);
+        let ends_session = input_generator.ends_session(&input);
+        // closed-loop requests have no schedule, they are due whenever a client is ready
+        let request = Request {
+            input,
+            scheduled_ns: None,
+            phase: bounds.phase(i, now),
+            session,
+        };
+        let sender = match session {
+            None => input_sender,
+            Some(session) => sessions.queue(session),
+        };
+
+        let sent = tokio::select! {
+            biased;
+            _ = shutdown.reached(ShutdownState::Draining) => break StopReason::Signal,
+            _ = sleep_until_some(bounds.end) => break StopReason::Duration,
+            _ = control.stopped() => break StopReason::Stopped,
+            sent = sender.send(request) => sent,
+        };
+        if sent.is_err() {
+            warn!("Input sender already closed while trying to generate more load");
+            break StopReason::ClientsClosed;
+        }
+        counters.sent.fetch_add(1, Ordering::Relaxed);
+        if let Some(session) = session.filter(|_| ends_session) {
+            sessions.end(session);
+        }
+
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            info!(done = i, total = ?bounds.total, "Progressing");
+        }
 
         i += 1;
-        if i >= total {
-            break;
+        if bounds.reached_total(i) {
+            break StopReason::Total;
         }
     }
+}
 
-    info!("Closing load sender");
-    input_sender.close();
-    info!("Closing input generator");
-    input_generator.close();
-
-    let total = tasks.len();
-    for (i, task) in tasks.into_iter().enumerate() {
-        debug!(task = i, total, "Waiting for task to finish");
-        match task.await {
-            Ok(outputs) => {
-                trace!(count = outputs.len(), "Sending outputs");
-                for output in outputs {
-                    output_sink.send(output).await;
+/// Sleep until the deadline, or forever if there isn't one.
+pub(crate) async fn sleep_until_some(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use std::{collections::HashMap, sync::atomic::AtomicU32};
+
+    use super::*;
+    use crate::{
+        clock::TokioClock,
+        input::Prefetch,
+        testing::{
+            batch_dispatcher, flaky_dispatcher, panic_dispatcher, sleep_dispatcher, FnDispatcher,
+            FnDispatcherGenerator, VecInputGenerator, VecOutputSink,
+        },
+        LoadController, MissedTickBehavior, Outcome, Output, PanicPolicy,
+    };
+
+    async fn run(options: LoadOptions, inputs: usize) -> (StopReason, Vec<Output<()>>) {
+        let mut sink = VecOutputSink::default();
+        let stop_reason = generate_load(
+            options,
+            VecInputGenerator::new(vec![(); inputs]),
+            FnDispatcher::noop().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap()
+        .stop_reason;
+        (stop_reason, sink.0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_closed_loop() {
+        let options = LoadOptions::builder()
+            .closed_loop(4, ThinkTime::Constant(Duration::from_millis(1)))
+            .total(100)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let (stop_reason, outputs) = run(options, 1000).await;
+        assert_eq!(stop_reason, StopReason::Total);
+        assert_eq!(outputs.len(), 100);
+        assert!(outputs.iter().all(|o| (1..=4).contains(&o.core.client)));
+        assert!(outputs
+            .iter()
+            .all(|o| o.core.scheduled_ns == o.core.start_ns));
+        // the clients take turns, each starting a request every 1ms of think time
+        let mut starts = HashMap::<u32, Vec<i64>>::new();
+        for o in &outputs {
+            starts
+                .entry(o.core.client)
+                .or_default()
+                .push(o.core.start_ns);
+        }
+        let expected: Vec<_> = (0..25).map(|i| i * 1_000_000).collect();
+        assert_eq!(starts.len(), 4);
+        assert!(starts.values().all(|starts| *starts == expected));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_phases() {
+        let options = LoadOptions::builder()
+            .rate(1000)
+            .total(10)
+            .warmup(PhaseLength::Requests(2))
+            .cooldown(PhaseLength::Requests(3))
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let (_, mut outputs) = run(options, 10).await;
+        outputs.sort_by_key(|o| o.core.scheduled_ns);
+        let phases: Vec<_> = outputs.iter().map(|o| o.core.phase).collect();
+        assert_eq!(phases[..2], [Phase::Warmup; 2]);
+        assert_eq!(phases[2..7], [Phase::Measure; 5]);
+        assert_eq!(phases[7..], [Phase::Cooldown; 3]);
+        let scheduled: Vec<_> = outputs.iter().map(|o| o.core.scheduled_ns).collect();
+        assert_eq!(
+            scheduled,
+            (1..=10).map(|i| i * 1_000_000).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_durations_too_long_to_represent() {
+        // the run can't reach the end of its duration, so stops at its total
+        let options = LoadOptions::builder()
+            .rate(10)
+            .total(3)
+            .duration(Duration::MAX)
+            .warmup(PhaseLength::Duration(Duration::MAX))
+            .cooldown(PhaseLength::Duration(Duration::from_secs(1)))
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let (stop_reason, outputs) = run(options, 10).await;
+        assert_eq!(stop_reason, StopReason::Total);
+        assert_eq!(outputs.len(), 3);
+        assert!(outputs.iter().all(|o| o.core.phase == Phase::Warmup));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stop_conditions() {
+        let options = || LoadOptions::builder().rate(1000).clock(TokioClock::new(0));
+        let (stop_reason, outputs) = run(options().total(20).build().unwrap(), 100).await;
+        assert_eq!(stop_reason, StopReason::Total);
+        assert_eq!(outputs.len(), 20);
+
+        let (stop_reason, outputs) = run(options().total(20).build().unwrap(), 10).await;
+        assert_eq!(stop_reason, StopReason::InputExhausted);
+        assert_eq!(outputs.len(), 10);
+
+        let options = options()
+            .total(1000)
+            .duration(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let (stop_reason, outputs) = run(options, 1000).await;
+        // the input due at the end of the duration isn't sent
+        assert_eq!(stop_reason, StopReason::Duration);
+        assert_eq!(outputs.len(), 19);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stops_when_profile_ends() {
+        let ramp_down = crate::profile::LoadProfile::Ramp {
+            from: 100.,
+            to: 0.,
+            over: Duration::from_secs(1),
+        };
+        let options = LoadOptions::builder()
+            .profile(ramp_down.clone())
+            .total(1000)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let (stop_reason, outputs) = run(options, 1000).await;
+        assert_eq!(stop_reason, StopReason::ProfileEnded);
+        assert_eq!(outputs.len(), 50);
+
+        // with a duration the run waits it out
+        let options = LoadOptions::builder()
+            .profile(ramp_down)
+            .duration(Duration::from_secs(2))
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let (stop_reason, outputs) = run(options, 1000).await;
+        assert_eq!(stop_reason, StopReason::Duration);
+        assert_eq!(outputs.len(), 50);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_timeouts() {
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .request_timeout(Duration::from_millis(20))
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let inputs = vec![
+            (Duration::ZERO, None),
+            (Duration::from_secs(10), None),
+            (Duration::from_millis(50), Some(Duration::from_secs(10))),
+        ];
+        let mut sink = VecOutputSink::default();
+        let stop_reason = generate_load(
+            options,
+            VecInputGenerator::new(inputs),
+            sleep_dispatcher().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap()
+        .stop_reason;
+        assert_eq!(stop_reason, StopReason::InputExhausted);
+        let mut outputs = sink.0;
+        outputs.sort_by_key(|o| o.core.iteration);
+        let outcomes: Vec<_> = outputs.iter().map(|o| o.core.outcome).collect();
+        assert_eq!(
+            outcomes,
+            [Outcome::Success, Outcome::Timeout, Outcome::Success]
+        );
+        assert!(outputs[1].core.error.is_none());
+        let times: Vec<_> = outputs
+            .iter()
+            .map(|o| (o.core.start_ns, o.core.end_ns))
+            .collect();
+        assert_eq!(times, [(0, 0), (0, 20_000_000), (20_000_000, 70_000_000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_spawns_clients_only_when_none_are_idle() {
+        let run = |max_clients| async move {
+            let options = LoadOptions::builder()
+                .rate(400)
+                .total(300)
+                .max_clients(max_clients)
+                .overload(OverloadPolicy::Drop)
+                .clock(TokioClock::new(0))
+                .build()
+                .unwrap();
+            let dispatcher = FnDispatcher::new(|()| async {
+                tokio::time::sleep(Duration::from_millis(6)).await;
+                Ok(())
+            });
+            generate_load(
+                options,
+                VecInputGenerator::new(vec![(); 300]),
+                dispatcher.generator(),
+                &mut VecOutputSink::default(),
+            )
+            .await
+            .unwrap()
+        };
+
+        // each request takes a little under three gaps, so three clients keep up by taking the
+        // next input once idle
+        let report = run(None).await;
+        assert_eq!(report.clients_spawned, 3);
+        assert_eq!(report.peak_in_flight, 3);
+        assert_eq!(report.inputs_dropped, 0);
+
+        // with two, between them taking an input every 3ms, only the inputs that they can't keep
+        // up with are dropped, 49 of the 300 due every 2.5ms
+        let report = run(Some(2)).await;
+        assert_eq!(report.clients_spawned, 2);
+        assert_eq!(report.inputs_sent + report.inputs_dropped, 300);
+        assert_eq!(report.inputs_dropped, 49);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_overload_policies() {
+        let run = |overload| async move {
+            let options = LoadOptions::builder()
+                .rate(1000)
+                .total(50)
+                .max_clients(Some(1))
+                .overload(overload)
+                .clock(TokioClock::new(0))
+                .build()
+                .unwrap();
+            let inputs = vec![(Duration::from_millis(10), None); 50];
+            let mut sink = VecOutputSink::default();
+            let report = generate_load(
+                options,
+                VecInputGenerator::new(inputs),
+                sleep_dispatcher().generator(),
+                &mut sink,
+            )
+            .await
+            .unwrap();
+            assert_eq!(sink.0.len(), 50);
+            let dropped: Vec<_> = sink.0.iter().filter(|o| o.is_dropped()).collect();
+            assert!(dropped.iter().all(|o| o.core.client == 0));
+
+            assert_eq!(report.stop_reason, StopReason::Total);
+            assert_eq!(report.inputs_generated, 50);
+            assert_eq!(report.inputs_dropped, dropped.len() as u64);
+            assert_eq!(report.inputs_sent + report.inputs_dropped, 50);
+            assert_eq!(report.clients_spawned, 1);
+            assert_eq!(report.peak_in_flight, 1);
+            assert!(report.failed_joins.is_empty());
+            let mut sent: Vec<_> = sink
+                .0
+                .iter()
+                .filter(|o| !o.is_dropped())
+                .map(|o| o.core.scheduled_ns / 1_000_000)
+                .collect();
+            sent.sort_unstable();
+            (sent, report.end_ns)
+        };
+
+        // blocking holds up every input after a busy client
+        let (sent, end_ns) = run(OverloadPolicy::Block).await;
+        assert_eq!(sent, (1..=50).collect::<Vec<_>>());
+        assert_eq!(end_ns, 501_000_000);
+        // the client takes the first input and one waiting for it, then one every 10ms
+        let (sent, end_ns) = run(OverloadPolicy::Drop).await;
+        assert_eq!(sent, [1, 2, 11, 21, 31, 41]);
+        assert_eq!(end_ns, 61_000_000);
+        // the queue fills up behind the first input, then refills as the client frees up
+        let (sent, end_ns) = run(OverloadPolicy::Queue { depth: 5 }).await;
+        assert_eq!(sent, [1, 2, 3, 4, 5, 6, 11, 21, 31, 41]);
+        assert_eq!(end_ns, 101_000_000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_missed_ticks() {
+        let run = |missed_ticks| async move {
+            let options = LoadOptions::builder()
+                .rate(1000)
+                .total(20)
+                .max_clients(Some(1))
+                .missed_ticks(missed_ticks)
+                .clock(TokioClock::new(0))
+                .build()
+                .unwrap();
+            let inputs = vec![(Duration::from_millis(5), None); 20];
+            generate_load(
+                options,
+                VecInputGenerator::new(inputs),
+                sleep_dispatcher().generator(),
+                &mut VecOutputSink::default(),
+            )
+            .await
+            .unwrap()
+            .schedule
+            .unwrap()
+        };
+
+        // a single client can only keep up with about 200 requests per second
+        let burst = run(MissedTickBehavior::Burst).await;
+        assert_eq!(burst.missed_ticks, 17);
+        assert!(burst.achieved_rate < burst.target_rate / 2.);
+        assert_eq!(burst.lag.max(), Duration::from_millis(71));
+
+        // only waiting for the busy client, rather than building up behind the schedule
+        let delay = run(MissedTickBehavior::Delay).await;
+        assert_eq!(delay.missed_ticks, 17);
+        assert!(delay.achieved_rate < delay.target_rate / 2.);
+        assert_eq!(delay.lag.max(), Duration::from_millis(5));
+
+        // skipping counts each of the inputs it skips
+        let skip = run(MissedTickBehavior::Skip).await;
+        assert_eq!(skip.missed_ticks, 65);
+        assert!(skip.achieved_rate < skip.target_rate / 2.);
+        assert_eq!(skip.lag.max(), Duration::from_millis(6));
+    }
+
+    /// Records when each output arrives, taking a while over each one.
+    struct SlowOutputSink(Vec<Instant>);
+
+    #[async_trait]
+    impl OutputSink<()> for SlowOutputSink {
+        async fn send(&mut self, _output: Output<()>) {
+            self.0.push(Instant::now());
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outputs_streamed_with_backpressure() {
+        let options = LoadOptions::builder()
+            .rate(100)
+            .total(20)
+            .output_buffer(1)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let mut sink = SlowOutputSink(Vec::new());
+        let start = Instant::now();
+        let stop_reason = generate_load(
+            options,
+            VecInputGenerator::new(vec![(); 100]),
+            FnDispatcher::noop().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap()
+        .stop_reason;
+        let end = Instant::now();
+        assert_eq!(stop_reason, StopReason::Total);
+        // each output reaches the sink as its request finishes, rather than at the end of the run
+        let arrived: Vec<_> = sink.0.iter().map(|at| *at - start).collect();
+        let due: Vec<_> = (1..=20).map(|i| Duration::from_millis(10) * i).collect();
+        assert_eq!(arrived, due);
+        assert_eq!(end - start, Duration::from_millis(201));
+    }
+
+    /// Blocks the thread over the first output until the clients have executed every input, like
+    /// a sink stuck writing to a file, giving up after a while.
+    struct BlockingOutputSink {
+        executed: Arc<AtomicU32>,
+        inputs: u32,
+        /// Whether every input was executed while the sink was blocked.
+        all_executed: Option<bool>,
+        received: usize,
+    }
+
+    #[async_trait]
+    impl OutputSink<()> for BlockingOutputSink {
+        async fn send(&mut self, _output: Output<()>) {
+            if self.all_executed.is_none() {
+                let give_up = std::time::Instant::now() + Duration::from_secs(10);
+                while self.executed.load(Ordering::Relaxed) < self.inputs
+                    && std::time::Instant::now() < give_up
+                {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                self.all_executed = Some(self.executed.load(Ordering::Relaxed) == self.inputs);
+            }
+            self.received += 1;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocking_sink_doesnt_hold_up_schedule() {
+        // time can't be paused on a multi threaded runtime, but nothing here depends on timing
+        // unless the generator is held up by the sink
+        let options = LoadOptions::builder().rate(1000).total(20).build().unwrap();
+        let executed = Arc::new(AtomicU32::new(0));
+        let dispatcher = FnDispatcher::new({
+            let executed = Arc::clone(&executed);
+            move |()| {
+                executed.fetch_add(1, Ordering::Relaxed);
+                async { Ok(()) }
+            }
+        });
+        let mut sink = BlockingOutputSink {
+            executed,
+            inputs: 20,
+            all_executed: None,
+            received: 0,
+        };
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![(); 20]),
+            dispatcher.generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.stop_reason, StopReason::Total);
+        assert_eq!(sink.all_executed, Some(true));
+        assert_eq!(sink.received, 20);
+    }
+
+    #[tokio::test]
+    async fn test_sharded_runs() {
+        for threads in [false, true] {
+            let options = LoadOptions::builder()
+                .rate(2000)
+                .total(200)
+                .shards(4)
+                .shard_threads(threads)
+                .build()
+                .unwrap();
+            let mut sink = VecOutputSink::default();
+            let mut shards_made = Vec::new();
+            let report = generate_load_sharded(
+                options,
+                |shard| {
+                    shards_made.push(shard);
+                    VecInputGenerator::new(vec![(); 1000])
+                },
+                |_| FnDispatcher::noop().generator(),
+                &mut sink,
+            )
+            .await;
+            assert_eq!(shards_made, [0, 1, 2, 3]);
+            assert_eq!(report.stop_reason, StopReason::Total);
+            assert_eq!(report.inputs_sent, 200);
+            assert_eq!(sink.0.len(), 200);
+
+            // every shard has its own clients, with IDs handed out across the whole run
+            assert!(report.clients_spawned >= 4);
+            assert!(sink
+                .0
+                .iter()
+                .all(|o| (1..=report.clients_spawned).contains(&o.core.client)));
+
+            let schedule = report.schedule.unwrap();
+            assert_eq!(schedule.lag.count(), 200);
+            assert!(
+                (1500. ..2500.).contains(&schedule.target_rate),
+                "{}",
+                schedule.target_rate
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsharded_run_rejects_shards() {
+        let options = LoadOptions::builder().total(10).shards(2).build().unwrap();
+        let mut sink = VecOutputSink::default();
+        let result = generate_load(
+            options,
+            VecInputGenerator::new(vec![(); 10]),
+            FnDispatcher::noop().generator(),
+            &mut sink,
+        )
+        .await;
+        assert_eq!(result, Err(LoadOptionsError::ShardsNeedSharded(2)));
+        assert!(sink.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_shards() {
+        for threads in [false, true] {
+            let options = LoadOptions::builder()
+                .rate(2000)
+                .total(200)
+                .shards(4)
+                .shard_threads(threads)
+                .build()
+                .unwrap();
+            let mut sink = VecOutputSink::default();
+            let report = generate_load_sharded(
+                options,
+                |shard| {
+                    (0..1000).map(move |_| {
+                        assert_ne!(shard, 1, "Shard panicked");
+                    })
+                },
+                |_| FnDispatcher::noop().generator(),
+                &mut sink,
+            )
+            .await;
+            // the other shards carry on to their share of the total
+            assert_eq!(report.stop_reason, StopReason::Total);
+            assert_eq!(report.inputs_sent, 150);
+            assert_eq!(sink.0.len(), 150);
+            let [failed] = &report.failed_joins[..] else {
+                panic!("{:?}", report.failed_joins);
+            };
+            assert_eq!((failed.shard, failed.client), (1, None));
+            assert!(failed.error.contains("Shard panicked"), "{}", failed.error);
+        }
+
+        // without other shards to carry on, the run has failed
+        let options = LoadOptions::builder()
+            .rate(2000)
+            .total(200)
+            .build()
+            .unwrap();
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            std::iter::from_fn(|| -> Option<()> { panic!("Shard panicked") }),
+            FnDispatcher::noop().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.stop_reason, StopReason::Failed);
+        assert_eq!(report.inputs_sent, 0);
+        assert_eq!(report.failed_joins.len(), 1);
+        assert_eq!(report.failed_joins[0].client, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_controller() {
+        let controller = LoadController::new();
+        controller.pause();
+        let options = LoadOptions::builder()
+            .rate(1000)
+            .clock(TokioClock::new(0))
+            .controller(controller.clone())
+            .build()
+            .unwrap();
+        let mut sink = VecOutputSink::default();
+        let run = generate_load(
+            options.clone(),
+            VecInputGenerator::new(vec![(); usize::MAX]),
+            FnDispatcher::noop().generator(),
+            &mut sink,
+        );
+        let control = async {
+            let wait = Duration::from_millis(200);
+            tokio::time::sleep(wait).await;
+            assert_eq!(controller.counters().inputs_sent, 0);
+            controller.resume();
+            tokio::time::sleep(wait).await;
+            let base = controller.counters().inputs_sent;
+            controller.set_rate(5000.);
+            tokio::time::sleep(wait).await;
+            let raised = controller.counters().inputs_sent - base;
+            controller.stop();
+            (base, raised)
+        };
+        let (report, (base, raised)) = tokio::join!(run, control);
+        let report = report.unwrap();
+
+        assert_eq!(report.stop_reason, StopReason::Stopped);
+        // the input due as the controller reads the counters isn't sent yet
+        assert_eq!(base, 199);
+        // the new rate takes effect once the loop wakes for the tick at the old rate
+        assert_eq!(raised, 995);
+        assert_eq!(sink.0.len() as u64, report.inputs_sent);
+        assert_eq!(controller.counters().inputs_sent, report.inputs_sent);
+        // the target rate follows the controller, not the profile
+        let target_rate = report.schedule.unwrap().target_rate;
+        assert_eq!(target_rate, 2000.);
+
+        // the next run starts its counters afresh and isn't stopped by the last run's stop, but
+        // keeps the rate
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![(); 10]),
+            FnDispatcher::noop().generator(),
+            &mut VecOutputSink::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.stop_reason, StopReason::InputExhausted);
+        assert_eq!(report.inputs_sent, 10);
+        assert_eq!(controller.counters().inputs_sent, 10);
+        assert_eq!(report.schedule.unwrap().target_rate, 5000.);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_controller_closed_loop() {
+        let controller = LoadController::new();
+        controller.pause();
+        let options = LoadOptions::builder()
+            .closed_loop(2, ThinkTime::Constant(Duration::from_millis(1)))
+            .clock(TokioClock::new(0))
+            .controller(controller.clone())
+            .build()
+            .unwrap();
+        let mut sink = VecOutputSink::default();
+        let run = generate_load(
+            options,
+            VecInputGenerator::new(vec![(); usize::MAX]),
+            FnDispatcher::noop().generator(),
+            &mut sink,
+        );
+        let control = async {
+            let wait = Duration::from_millis(50);
+            tokio::time::sleep(wait).await;
+            assert_eq!(controller.counters().inputs_sent, 0);
+            controller.resume();
+            tokio::time::sleep(wait).await;
+            let sent = controller.counters().inputs_sent;
+            controller.stop();
+            sent
+        };
+        let (report, sent) = tokio::join!(run, control);
+        let report = report.unwrap();
+
+        assert_eq!(report.stop_reason, StopReason::Stopped);
+        // each of the two clients sends once every millisecond of think time
+        assert_eq!(sent, 101);
+        assert_eq!(sink.0.len() as u64, report.inputs_sent);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paused_time_is_deterministic() {
+        async fn run_paused() -> Vec<(i64, i64, i64)> {
+            let options = LoadOptions::builder()
+                .rate(10)
+                .clock(TokioClock::new(0))
+                .build()
+                .unwrap();
+            let inputs = (0..100)
+                .map(|i| (Duration::from_millis(50 + i), None))
+                .collect::<Vec<_>>();
+            let mut sink = VecOutputSink::default();
+            generate_load(
+                options,
+                VecInputGenerator::new(inputs),
+                sleep_dispatcher().generator(),
+                &mut sink,
+            )
+            .await
+            .unwrap();
+            let mut outputs = sink.0;
+            outputs.sort_by_key(|o| o.core.scheduled_ns);
+            outputs
+                .iter()
+                .map(|o| (o.core.scheduled_ns, o.core.start_ns, o.core.end_ns))
+                .collect()
+        }
+
+        // 10 seconds of load each, without waiting for it
+        let real_start = std::time::Instant::now();
+        let first = run_paused().await;
+        let second = run_paused().await;
+        assert!(real_start.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(first, second);
+        for (i, (scheduled_ns, start_ns, end_ns)) in first.into_iter().enumerate() {
+            let i = i as i64;
+            assert_eq!(scheduled_ns, (i + 1) * 100_000_000);
+            assert_eq!(start_ns, scheduled_ns);
+            assert_eq!(end_ns - start_ns, (50 + i) * 1_000_000);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paused_time_stamps_errors_and_timeouts() {
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .request_timeout(Duration::from_millis(20))
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let dispatcher = FnDispatcher::new(|(millis, fail): (u64, bool)| async move {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            if fail {
+                Err("failed".to_owned())
+            } else {
+                Ok(())
+            }
+        });
+        let mut sink = VecOutputSink::default();
+        generate_load(
+            options,
+            VecInputGenerator::new(vec![(5, false), (10, true), (50, false)]),
+            dispatcher.generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        // every end comes from the run's clock, whatever the outcome
+        let outputs: Vec<_> = sink
+            .0
+            .iter()
+            .map(|o| (o.core.outcome, o.core.end_ns - o.core.start_ns))
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                (Outcome::Success, 5_000_000),
+                (Outcome::Error, 10_000_000),
+                (Outcome::Timeout, 20_000_000)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_panics() {
+        for (on_panic, dispatchers) in [(PanicPolicy::Continue, 1), (PanicPolicy::Replace, 2)] {
+            let options = LoadOptions::builder()
+                .closed_loop(1, ThinkTime::None)
+                .on_panic(on_panic)
+                .build()
+                .unwrap();
+            let generated = Arc::new(AtomicU32::new(0));
+            let dispatcher = panic_dispatcher();
+            let generator = FnDispatcherGenerator({
+                let generated = Arc::clone(&generated);
+                move || {
+                    generated.fetch_add(1, Ordering::Relaxed);
+                    dispatcher.clone()
+                }
+            });
+            let mut sink = VecOutputSink::default();
+            let report = generate_load(
+                options,
+                VecInputGenerator::new(vec![false, true, false, false]),
+                generator,
+                &mut sink,
+            )
+            .await
+            .unwrap();
+
+            assert!(report.failed_joins.is_empty());
+            assert_eq!(generated.load(Ordering::Relaxed), dispatchers);
+            // the client carries on after the panic
+            let outputs = sink.0;
+            assert_eq!(outputs.len(), 4);
+            assert!(outputs.iter().all(|o| o.core.client == 1));
+            let errors: Vec<_> = outputs.iter().filter(|o| o.is_error()).collect();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].core.iteration, 1);
+            assert_eq!(
+                errors[0].core.error.as_deref(),
+                Some("dispatcher panicked: boom")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panics_outside_of_execute() {
+        // the replacement can't be generated, so the client keeps its dispatcher
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .build()
+            .unwrap();
+        let generated = Arc::new(AtomicU32::new(0));
+        let dispatcher = panic_dispatcher();
+        let generator = FnDispatcherGenerator({
+            let generated = Arc::clone(&generated);
+            move || {
+                if generated.fetch_add(1, Ordering::Relaxed) > 0 {
+                    panic!("no more dispatchers");
+                }
+                dispatcher.clone()
+            }
+        });
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![false, true, true, false]),
+            generator,
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert!(report.failed_joins.is_empty());
+        assert_eq!(generated.load(Ordering::Relaxed), 3);
+        assert_eq!(sink.0.len(), 4);
+        assert_eq!(sink.0.iter().filter(|o| o.is_error()).count(), 2);
+
+        // nor do panics in the dispatcher's other methods take down the client
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .build()
+            .unwrap();
+        let dispatcher = FnDispatcher::new(|_: bool| async { Ok(()) }).timeout(|panic| {
+            if *panic {
+                panic!("bad timeout");
+            }
+            None
+        });
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![false, true, false]),
+            dispatcher.generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert!(report.failed_joins.is_empty());
+        let mut outputs = sink.0;
+        outputs.sort_by_key(|o| o.core.iteration);
+        let errors: Vec<_> = outputs.iter().map(|o| o.core.error.as_deref()).collect();
+        assert_eq!(
+            errors,
+            [None, Some("dispatcher panicked: bad timeout"), None]
+        );
+
+        // a client whose dispatcher can't be generated isn't started, leaving the others running
+        let options = LoadOptions::builder()
+            .closed_loop(2, ThinkTime::None)
+            .build()
+            .unwrap();
+        let generated = Arc::new(AtomicU32::new(0));
+        let dispatcher = panic_dispatcher();
+        let generator = FnDispatcherGenerator({
+            let generated = Arc::clone(&generated);
+            move || {
+                if generated.fetch_add(1, Ordering::Relaxed) == 1 {
+                    panic!("no second dispatcher");
+                }
+                dispatcher.clone()
+            }
+        });
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![false; 4]),
+            generator,
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.stop_reason, StopReason::InputExhausted);
+        assert_eq!(sink.0.len(), 4);
+        assert!(sink.0.iter().all(|o| o.core.client == 1));
+        let [failed] = &report.failed_joins[..] else {
+            panic!("{:?}", report.failed_joins);
+        };
+        assert_eq!(failed.client, Some(2));
+        assert_eq!(
+            failed.error,
+            "dispatcher generator panicked: no second dispatcher"
+        );
+
+        // and without any clients running the run stops
+        let options = LoadOptions::builder().rate(1000).build().unwrap();
+        let generator =
+            FnDispatcherGenerator(|| -> FnDispatcher<bool, ()> { panic!("no dispatchers") });
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![false; 4]),
+            generator,
+            &mut VecOutputSink::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.stop_reason, StopReason::ClientsClosed);
+        let clients: Vec<_> = report.failed_joins.iter().map(|f| f.client).collect();
+        assert_eq!(clients, [Some(1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries() {
+        let retry = RetryPolicy::new(3)
+            .backoff(Duration::from_millis(10), Duration::from_secs(1))
+            .jitter(0.)
+            .retryable(|error| error != "fatal")
+            .record_attempts(true);
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .retry(retry)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let inputs = [
+            (0, "transient"),
+            (2, "transient"),
+            (5, "transient"),
+            (1, "fatal"),
+        ]
+        .map(|(failures, error)| (Arc::new(AtomicU32::new(failures)), error));
+        let inputs = Vec::from(inputs);
+        let mut sink = VecOutputSink::default();
+        generate_load(
+            options,
+            VecInputGenerator::new(inputs),
+            flaky_dispatcher().retrying().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        let mut outputs = sink.0;
+        outputs.sort_by_key(|o| o.core.iteration);
+        let summary: Vec<_> = outputs
+            .iter()
+            .map(|o| {
+                (
+                    o.core.attempts,
+                    o.core.error.as_deref(),
+                    o.core.attempt_latencies_ns.len() as u32,
+                    o.core.end_ns - o.core.start_ns,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1, None, 1, 0),
+                // backing off for 10ms and then 20ms
+                (3, None, 3, 30_000_000),
+                (3, Some("transient"), 3, 30_000_000),
+                (1, Some("fatal"), 1, 0),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clients_dying_stops_closed_loop() {
+        // the predicate is called outside of the dispatcher, so its panic takes down the client
+        let retry = RetryPolicy::new(2).retryable(|_| panic!("bad predicate"));
+        let options = LoadOptions::builder()
+            .closed_loop(2, ThinkTime::None)
+            .retry(retry)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let inputs = (0..10).map(|_| (Arc::new(AtomicU32::new(1)), "transient"));
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(inputs.collect()),
+            flaky_dispatcher().retrying().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.stop_reason, StopReason::ClientsClosed);
+        assert!(sink.0.is_empty());
+        let clients: Vec<_> = report.failed_joins.iter().map(|f| f.client).collect();
+        assert_eq!(clients, [Some(1), Some(2)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clients_dying_stops_blocked_open_loop() {
+        let retry = RetryPolicy::new(2).retryable(|_| panic!("bad predicate"));
+        let options = LoadOptions::builder()
+            .rate(1000)
+            .total(50)
+            .max_clients(Some(1))
+            .overload(OverloadPolicy::Block)
+            .retry(retry)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        // the client dies once its first request fails, while the generator waits for it
+        let dispatcher = FnDispatcher::<(), ()>::new(|()| async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            Err("transient".to_owned())
+        });
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![(); 50]),
+            dispatcher.retrying().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        // the client can't be replaced with the maximum reached
+        assert_eq!(report.stop_reason, StopReason::ClientsClosed);
+        assert_eq!(report.clients_spawned, 1);
+        assert!(sink.0.is_empty());
+        let clients: Vec<_> = report.failed_joins.iter().map(|f| f.client).collect();
+        assert_eq!(clients, [Some(1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_need_retry_input() {
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .retry(RetryPolicy::new(3).jitter(0.))
+            .build()
+            .unwrap();
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![(Arc::new(AtomicU32::new(1)), "transient")]),
+            flaky_dispatcher().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        // without a copy of the input there's nothing to retry with
+        let outputs = sink.0;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].core.attempts, 1);
+        assert_eq!(outputs[0].core.error.as_deref(), Some("transient"));
+        assert_eq!(report.retries_skipped, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_concurrency() {
+        for (pipelined, most_in_flight) in [(true, 4), (false, 1)] {
+            let options = LoadOptions::builder()
+                .closed_loop(1, ThinkTime::None)
+                .client_concurrency(4)
+                .clock(TokioClock::new(0))
+                .build()
+                .unwrap();
+            // takes 10ms over each request, keeping track of the most it has had at once
+            let in_flight = Arc::new(AtomicU32::new(0));
+            let most = Arc::new(AtomicU32::new(0));
+            let mut dispatcher = FnDispatcher::new({
+                let most = Arc::clone(&most);
+                move |()| {
+                    let in_flight = Arc::clone(&in_flight);
+                    let most = Arc::clone(&most);
+                    async move {
+                        let now = in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+                        most.fetch_max(now, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        in_flight.fetch_sub(1, Ordering::Relaxed);
+                        Ok(())
+                    }
                 }
+            });
+            if pipelined {
+                dispatcher = dispatcher.concurrent();
             }
-            Err(error) => {
-                warn!(%error, task=i, "Failed to join task");
+            let mut sink = VecOutputSink::default();
+            let report = generate_load(
+                options,
+                VecInputGenerator::new(vec![(); 20]),
+                dispatcher.generator(),
+                &mut sink,
+            )
+            .await
+            .unwrap();
+
+            assert!(report.failed_joins.is_empty());
+            assert_eq!(most.load(Ordering::Relaxed), most_in_flight);
+            // every request gets its own output, all from the one client
+            let outputs = sink.0;
+            assert_eq!(outputs.len(), 20);
+            assert!(outputs.iter().all(|o| o.core.client == 1));
+            let mut iterations: Vec<_> = outputs.iter().map(|o| o.core.iteration).collect();
+            iterations.sort();
+            assert_eq!(iterations, (0..20).collect::<Vec<_>>());
+            let elapsed_ns = outputs.iter().map(|o| o.core.end_ns).max().unwrap();
+            assert_eq!(elapsed_ns, 200_000_000 / most_in_flight as i64);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batches() {
+        // fills up batches as quickly as the inputs come
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .batch(4, Duration::from_secs(1))
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let batches = Arc::default();
+        let mut sink = VecOutputSink::default();
+        let inputs = (0..10).map(|i| i == 5).collect::<Vec<_>>();
+        generate_load(
+            options,
+            VecInputGenerator::new(inputs),
+            batch_dispatcher(&batches).generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*batches.lock().unwrap(), [4, 4, 2]);
+        let mut outputs = sink.0;
+        outputs.sort_by_key(|o| o.core.iteration);
+        let summary: Vec<_> = outputs
+            .iter()
+            .map(|o| (o.core.batch_id, o.core.batch_size, o.is_error()))
+            .collect();
+        let mut expected: Vec<_> = [(0, 4), (1, 4), (2, 2)]
+            .into_iter()
+            .flat_map(|(id, size)| vec![(Some(id), size, false); size as usize])
+            .collect();
+        // only the input that failed gets an error
+        expected[5].2 = true;
+        assert_eq!(summary, expected);
+
+        // or sends what has been gathered when the wait is up
+        let options = LoadOptions::builder()
+            .rate(100)
+            .total(10)
+            .batch(100, Duration::from_millis(35))
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let batches = Arc::default();
+        let mut sink = VecOutputSink::default();
+        generate_load(
+            options,
+            VecInputGenerator::new(vec![false; 10]),
+            batch_dispatcher(&batches).generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*batches.lock().unwrap(), [4, 4, 2]);
+        assert_eq!(sink.0.len(), 10);
+
+        // without waiting, batches are made from the inputs already queued
+        let options = LoadOptions::builder()
+            .rate(1000)
+            .total(9)
+            .max_clients(Some(1))
+            .overload(OverloadPolicy::Queue { depth: 10 })
+            .batch(4, Duration::ZERO)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let batches = Arc::default();
+        let mut sink = VecOutputSink::default();
+        generate_load(
+            options,
+            VecInputGenerator::new(vec![false; 9]),
+            batch_dispatcher(&batches).generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*batches.lock().unwrap(), [1, 4, 4]);
+        assert_eq!(sink.0.len(), 9);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_wait_too_long_to_represent() {
+        // the last batch is sent once the inputs run out rather than at the end of the wait
+        let options = LoadOptions::builder()
+            .closed_loop(1, ThinkTime::None)
+            .batch(4, Duration::MAX)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap();
+        let batches = Arc::default();
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options,
+            VecInputGenerator::new(vec![false; 10]),
+            batch_dispatcher(&batches).generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.stop_reason, StopReason::InputExhausted);
+        assert!(report.failed_joins.is_empty());
+        assert_eq!(*batches.lock().unwrap(), [4, 4, 2]);
+        assert_eq!(sink.0.len(), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sessions() {
+        let open = || LoadOptions::builder().rate(1000).max_clients(Some(3));
+        let closed = LoadOptions::builder().closed_loop(3, ThinkTime::None);
+        // the clients fall behind, but a session's inputs wait for its client whatever the policy
+        let dropping = open().overload(OverloadPolicy::Drop);
+        let queueing = open().overload(OverloadPolicy::Queue { depth: 2 });
+        for options in [open(), closed, dropping, queueing] {
+            let options = options.clock(TokioClock::new(0)).build().unwrap();
+            // interleave the steps of the journeys, as `(session, step)`, each ending after its
+            // fourth step
+            let inputs: Vec<(u64, u32)> = (0..4)
+                .flat_map(|step| (1..=5).map(move |session| (session, step)))
+                .collect();
+            let inputs = VecInputGenerator::new(inputs)
+                .sessions(|(session, _)| Some(*session), |(_, step)| *step == 3);
+            // logs the steps as they are executed, taking longer over later sessions
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let dispatcher = FnDispatcher::new({
+                let log = Arc::clone(&log);
+                move |(session, step)| {
+                    let log = Arc::clone(&log);
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(session)).await;
+                        log.lock().unwrap().push((session, step));
+                        Ok(())
+                    }
+                }
+            });
+            let mut sink = VecOutputSink::default();
+            generate_load(options, inputs, dispatcher.generator(), &mut sink)
+                .await
+                .unwrap();
+
+            let outputs = sink.0;
+            assert_eq!(outputs.len(), 20);
+            assert!(outputs.iter().all(|o| !o.is_dropped()));
+            let clients: HashMap<_, _> = outputs
+                .iter()
+                .map(|o| (o.core.session.unwrap(), o.core.client))
+                .collect();
+            for session in 1..=5 {
+                // every step of the journey ran on the same client, in order
+                let client = clients[&session];
+                assert!(outputs
+                    .iter()
+                    .filter(|o| o.core.session == Some(session))
+                    .all(|o| o.core.client == client));
+                let steps: Vec<_> = log
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(s, _)| *s == session)
+                    .map(|(_, step)| *step)
+                    .collect();
+                assert_eq!(steps, [0, 1, 2, 3]);
+            }
+            let mut used: Vec<_> = clients.into_values().collect();
+            used.sort();
+            used.dedup();
+            assert_eq!(used.len(), 3);
+        }
+    }
+
+    /// Takes 5ms over every 10th input, such as when reading the next page of them, counting the
+    /// inputs taken from the current page.
+    struct PagedInputGenerator(usize);
+
+    #[async_trait]
+    impl input::AsyncInputGenerator for PagedInputGenerator {
+        type Input = ();
+
+        async fn next(&mut self) -> Option<Self::Input> {
+            self.0 += 1;
+            if self.0 == 10 {
+                self.0 = 0;
+                tokio::time::sleep(Duration::from_millis(5)).await;
             }
+            Some(())
         }
+
+        async fn close(self) {}
     }
 
-    info!(clients=%client_counter, "Finished generating load");
+    #[tokio::test(start_paused = true)]
+    async fn test_prefetch() {
+        let options = || {
+            LoadOptions::builder()
+                .rate(1000)
+                .total(100)
+                .missed_ticks(MissedTickBehavior::Burst)
+                .clock(TokioClock::new(0))
+                .build()
+                .unwrap()
+        };
+
+        // waiting for each page holds up the schedule
+        let report = generate_load(
+            options(),
+            PagedInputGenerator(0),
+            FnDispatcher::noop().generator(),
+            &mut VecOutputSink::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.stop_reason, StopReason::Total);
+        assert!(report.schedule.unwrap().missed_ticks > 0);
+
+        // but the pages can be read ahead of time
+        let mut sink = VecOutputSink::default();
+        let report = generate_load(
+            options(),
+            Prefetch::new(PagedInputGenerator(0), 20),
+            FnDispatcher::noop().generator(),
+            &mut sink,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.stop_reason, StopReason::Total);
+        assert_eq!(report.schedule.unwrap().missed_ticks, 0);
+        assert_eq!(sink.0.len(), 100);
+    }
 }
@@ -0,0 +1,93 @@
+//! How requests are driven: open-loop at a target rate, or closed-loop by a fixed set of clients.
+
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// The model used to drive requests.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum LoadMode {
+    /// Send requests at the rate given by the load profile, independent of how quickly they
+    /// complete, creating clients as needed to keep up.
+    #[default]
+    Open,
+    /// A fixed population of clients, each sending its next request as soon as its previous one
+    /// completes and it has waited for the think time.
+    ///
+    /// The load profile and arrival process are not used in this mode.
+    Closed { clients: u32, think_time: ThinkTime },
+}
+
+/// Time a closed-loop client waits between finishing one request and sending the next.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ThinkTime {
+    /// Send the next request straight away.
+    #[default]
+    None,
+    /// Always wait the same time.
+    Constant(Duration),
+    /// Wait for a uniformly random time between `min` and `max`.
+    Uniform { min: Duration, max: Duration },
+    /// Wait for an exponentially distributed time with the given mean.
+    Exponential { mean: Duration },
+}
+
+impl ThinkTime {
+    /// Check that the think time can be sampled, giving the reason if not.
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            Self::Uniform { min, max } if min > max => {
+                Err("uniform minimum must not be above the maximum")
+            }
+            Self::Exponential { mean } if mean.is_zero() => {
+                Err("exponential mean must not be zero")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Build a sampler for the think time, seeding any randomness with `seed`.
+    pub(crate) fn sampler(&self, seed: u64) -> ThinkTimeSampler {
+        ThinkTimeSampler {
+            think_time: self.clone(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+/// Draws think times for a single client.
+pub(crate) struct ThinkTimeSampler {
+    think_time: ThinkTime,
+    rng: StdRng,
+}
+
+impl ThinkTimeSampler {
+    pub(crate) fn next(&mut self) -> Duration {
+        match &self.think_time {
+            ThinkTime::None => Duration::ZERO,
+            ThinkTime::Constant(duration) => *duration,
+            ThinkTime::Uniform { min, max } => self.rng.gen_range(*min..=*max),
+            ThinkTime::Exponential { mean } => {
+                let u: f64 = self.rng.gen();
+                // the tail of a long mean can go past the longest duration
+                Duration::try_from_secs_f64(mean.as_secs_f64() * -(1. - u).ln())
+                    .unwrap_or(Duration::MAX)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_think_time_saturates() {
+        let mut sampler = ThinkTime::Exponential {
+            mean: Duration::MAX,
+        }
+        .sampler(0);
+        let think_times: Vec<_> = (0..100).map(|_| sampler.next()).collect();
+        assert!(think_times.contains(&Duration::MAX));
+    }
+}
@@ -0,0 +1,1138 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use crate::{
+    arrival::Arrival,
+    clock::{Clock, SystemClock},
+    controller::LoadController,
+    mode::{LoadMode, ThinkTime},
+    profile::LoadProfile,
+    retry::RetryPolicy,
+};
+
+/// The highest rate that can be paced, one request per nanosecond.
+pub(crate) const MAX_RATE: f64 = 1_000_000_000.;
+
+/// Settings for a load generation run.
+///
+/// Construct with [`LoadOptions::builder`], which validates the values so that a run can't start
+/// with settings that would make it panic part way through.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub(crate) mode: LoadMode,
+    pub(crate) profile: LoadProfile,
+    pub(crate) initial_clients: u32,
+    pub(crate) total: Option<u64>,
+    pub(crate) duration: Option<Duration>,
+    pub(crate) max_clients: Option<u32>,
+    pub(crate) arrival: Arrival,
+    pub(crate) seed: Option<u64>,
+    pub(crate) warmup: Option<PhaseLength>,
+    pub(crate) cooldown: Option<PhaseLength>,
+    pub(crate) handle_signals: bool,
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) output_buffer: usize,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) overload: OverloadPolicy,
+    pub(crate) missed_ticks: MissedTickBehavior,
+    pub(crate) pacing: Pacing,
+    pub(crate) shards: u32,
+    pub(crate) shard_threads: bool,
+    pub(crate) controller: Option<LoadController>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) on_panic: PanicPolicy,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) client_concurrency: u32,
+    pub(crate) batch_size: u32,
+    pub(crate) batch_wait: Duration,
+}
+
+/// What a client does after its dispatcher panics during a request, which is recorded as an
+/// error with the panic message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Carry on with the same dispatcher.
+    Continue,
+    /// Carry on with a freshly generated dispatcher, as the old one may have been left part way
+    /// through a request.
+    ///
+    /// If the dispatcher generator panics as well, the client carries on with the old dispatcher.
+    ///
+    /// With client concurrency, only the task whose handle panicked gets the new dispatcher, so
+    /// from then on it no longer shares a connection with the client's other tasks.
+    #[default]
+    Replace,
+}
+
+/// What an open-loop run does with an input that is due when all clients are busy and no more can
+/// be spawned.
+///
+/// Inputs that are part of a session always wait for the session's client, whatever the policy,
+/// so that every journey runs whole and in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverloadPolicy {
+    /// Wait for a client to become free, holding up the inputs after it.
+    ///
+    /// This keeps every input but effectively turns the run into a closed loop while it lasts.
+    #[default]
+    Block,
+    /// Drop the input, recording it as a dropped output.
+    Drop,
+    /// Queue up to `depth` inputs for the next free client, dropping any more.
+    Queue { depth: usize },
+}
+
+/// What an open-loop run does when it falls behind its schedule, such as after blocking on a busy
+/// client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissedTickBehavior {
+    /// Send the late inputs straight away to catch up with the schedule.
+    #[default]
+    Burst,
+    /// Push the rest of the schedule back so that the late input is due now.
+    ///
+    /// Later inputs are stamped with the delayed schedule, so latency no longer includes the time
+    /// that the generator fell behind by.
+    Delay,
+    /// Skip the inputs that were missed, without generating them.
+    Skip,
+}
+
+/// How an open-loop run waits for each input to become due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pacing {
+    /// Sleep on the tokio timer until each input is due.
+    ///
+    /// The timer has millisecond granularity, so at high rates the inputs due within each
+    /// millisecond are sent together in a burst.
+    #[default]
+    Sleep,
+    /// Sleep until shortly before each input is due and then spin until it is, yielding to other
+    /// tasks.
+    ///
+    /// This spaces inputs evenly at rates of hundreds of thousands per second, at the cost of
+    /// keeping a core busy while the run is going.
+    /// Spinning never lets paused tokio time skip ahead, so use [`Pacing::Sleep`] with paused
+    /// time.
+    Spin,
+}
+
+/// Length of a warmup or cooldown phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseLength {
+    /// A number of requests.
+    Requests(u64),
+    /// A length of time.
+    Duration(Duration),
+}
+
+impl LoadOptions {
+    /// Start building a set of options, with the defaults filled in.
+    ///
+    /// **The defaults have no total or duration**, so a run with these options only stops when
+    /// its input generator runs out, it is stopped with a [`LoadController`] or, if handling
+    /// signals, on SIGINT or SIGTERM. Set a [`total`](LoadOptionsBuilder::total) or
+    /// [`duration`](LoadOptionsBuilder::duration) for a run with an endless input generator to
+    /// stop by itself.
+    pub fn builder() -> LoadOptionsBuilder {
+        LoadOptionsBuilder::default()
+    }
+
+    /// The model used to drive requests.
+    pub fn mode(&self) -> &LoadMode {
+        &self.mode
+    }
+
+    /// Target number of requests per second over the course of the run.
+    pub fn profile(&self) -> &LoadProfile {
+        &self.profile
+    }
+
+    /// Number of clients to start before generating any load.
+    pub fn initial_clients(&self) -> u32 {
+        self.initial_clients
+    }
+
+    /// Total number of requests to generate before stopping, if bounded.
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    /// Length of time to generate requests for before stopping, if bounded.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Upper bound on the number of clients, unbounded if `None`.
+    pub fn max_clients(&self) -> Option<u32> {
+        self.max_clients
+    }
+
+    /// Most requests that each client executes at once.
+    pub fn client_concurrency(&self) -> u32 {
+        self.client_concurrency
+    }
+
+    /// Most inputs that clients gather into a batch, 1 if they don't batch inputs.
+    pub fn batch_size(&self) -> u32 {
+        self.batch_size
+    }
+
+    /// Longest that clients wait for a batch to fill up.
+    pub fn batch_wait(&self) -> Duration {
+        self.batch_wait
+    }
+
+    /// What to do with inputs when all of the clients are busy.
+    pub fn overload(&self) -> OverloadPolicy {
+        self.overload
+    }
+
+    /// What to do when the run falls behind its schedule.
+    pub fn missed_ticks(&self) -> MissedTickBehavior {
+        self.missed_ticks
+    }
+
+    /// How the run waits for each input to become due.
+    pub fn pacing(&self) -> Pacing {
+        self.pacing
+    }
+
+    /// Number of independent generator and client groups that the run is split across.
+    pub fn shards(&self) -> u32 {
+        self.shards
+    }
+
+    /// Whether each shard runs on its own thread.
+    pub fn shard_threads(&self) -> bool {
+        self.shard_threads
+    }
+
+    /// The controller for changing the run while it is going, if it has one.
+    pub fn controller(&self) -> Option<&LoadController> {
+        self.controller.as_ref()
+    }
+
+    /// What clients do after their dispatcher panics.
+    pub fn on_panic(&self) -> PanicPolicy {
+        self.on_panic
+    }
+
+    /// How clients retry requests that fail.
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// The clock that timestamps are read from.
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// The arrival process used to space out requests.
+    pub fn arrival(&self) -> &Arrival {
+        &self.arrival
+    }
+
+    /// Seed for any randomness in the run, chosen at the start of the run if `None`.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Length of the warmup phase at the start of the run.
+    pub fn warmup(&self) -> Option<PhaseLength> {
+        self.warmup
+    }
+
+    /// Length of the cooldown phase at the end of the run.
+    pub fn cooldown(&self) -> Option<PhaseLength> {
+        self.cooldown
+    }
+
+    /// Whether SIGINT and SIGTERM stop the run gracefully.
+    pub fn handle_signals(&self) -> bool {
+        self.handle_signals
+    }
+
+    /// How long in-flight requests get to finish after a signal.
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    /// Number of outputs that can be waiting for the sink before clients wait for it.
+    pub fn output_buffer(&self) -> usize {
+        self.output_buffer
+    }
+
+    /// How long a request can take before it is recorded as timed out.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// The options for one of `shards` shards, each taking its share of the totals and clients.
+    ///
+    /// The rate is split by the shard's schedule instead, so that shards can be offset from each
+    /// other.
+    pub(crate) fn shard(&self, shard: u32, shards: u32) -> Self {
+        let split =
+            |value: u64| value / shards as u64 + u64::from((shard as u64) < value % shards as u64);
+        let split_clients = |clients: u32| split(clients as u64) as u32;
+        let split_phase = |length: PhaseLength| match length {
+            PhaseLength::Requests(requests) => PhaseLength::Requests(split(requests)),
+            PhaseLength::Duration(duration) => PhaseLength::Duration(duration),
+        };
+        let mode = match &self.mode {
+            LoadMode::Open => LoadMode::Open,
+            LoadMode::Closed {
+                clients,
+                think_time,
+            } => LoadMode::Closed {
+                clients: split_clients(*clients),
+                think_time: think_time.clone(),
+            },
+        };
+        Self {
+            mode,
+            initial_clients: split_clients(self.initial_clients),
+            total: self.total.map(split),
+            max_clients: self.max_clients.map(split_clients),
+            warmup: self.warmup.map(split_phase),
+            cooldown: self.cooldown.map(split_phase),
+            shards: 1,
+            ..self.clone()
+        }
+    }
+}
+
+/// Builder for [`LoadOptions`].
+///
+/// Stop conditions combine, with the run ending at whichever is reached first. A run always
+/// stops when the input generator runs out of inputs. Without a total or duration, that and
+/// the controller or signals are the only ways that it stops.
+#[derive(Debug, Clone)]
+pub struct LoadOptionsBuilder {
+    mode: LoadMode,
+    profile: LoadProfile,
+    initial_clients: u32,
+    total: Option<u64>,
+    duration: Option<Duration>,
+    max_clients: Option<u32>,
+    arrival: Arrival,
+    seed: Option<u64>,
+    warmup: Option<PhaseLength>,
+    cooldown: Option<PhaseLength>,
+    handle_signals: bool,
+    shutdown_timeout: Duration,
+    output_buffer: usize,
+    request_timeout: Option<Duration>,
+    overload: OverloadPolicy,
+    missed_ticks: MissedTickBehavior,
+    pacing: Pacing,
+    shards: u32,
+    shard_threads: bool,
+    controller: Option<LoadController>,
+    clock: Arc<dyn Clock>,
+    on_panic: PanicPolicy,
+    retry: RetryPolicy,
+    client_concurrency: u32,
+    batch_size: u32,
+    batch_wait: Duration,
+}
+
+impl Default for LoadOptionsBuilder {
+    fn default() -> Self {
+        Self {
+            mode: LoadMode::default(),
+            profile: LoadProfile::Constant(100.),
+            initial_clients: 0,
+            total: None,
+            duration: None,
+            max_clients: None,
+            arrival: Arrival::default(),
+            seed: None,
+            warmup: None,
+            cooldown: None,
+            handle_signals: false,
+            shutdown_timeout: Duration::from_secs(30),
+            output_buffer: 1024,
+            request_timeout: None,
+            overload: OverloadPolicy::default(),
+            missed_ticks: MissedTickBehavior::default(),
+            pacing: Pacing::default(),
+            shards: 1,
+            shard_threads: false,
+            controller: None,
+            clock: Arc::new(SystemClock),
+            on_panic: PanicPolicy::default(),
+            retry: RetryPolicy::default(),
+            client_concurrency: 1,
+            batch_size: 1,
+            batch_wait: Duration::ZERO,
+        }
+    }
+}
+
+impl LoadOptionsBuilder {
+    /// Set a constant target number of requests per second.
+    pub fn rate(self, rate: u64) -> Self {
+        self.profile(LoadProfile::Constant(rate as f64))
+    }
+
+    /// Set the target number of requests per second to vary over the course of the run.
+    pub fn profile(mut self, profile: LoadProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Drive requests in a closed loop with a fixed number of clients, each waiting for the think
+    /// time between requests, rather than at the rate of the load profile.
+    pub fn closed_loop(mut self, clients: u32, think_time: ThinkTime) -> Self {
+        self.mode = LoadMode::Closed {
+            clients,
+            think_time,
+        };
+        self
+    }
+
+    /// Set the number of clients to start before generating any load.
+    pub fn initial_clients(mut self, initial_clients: u32) -> Self {
+        self.initial_clients = initial_clients;
+        self
+    }
+
+    /// Stop after generating a total number of requests.
+    pub fn total(mut self, total: u64) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    /// Stop after generating requests for a length of time.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Set the upper bound on the number of clients.
+    pub fn max_clients(mut self, max_clients: Option<u32>) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    /// Set the most requests that each client executes at once, 1 by default.
+    ///
+    /// Clients execute concurrent requests with handles from
+    /// [`Dispatcher::concurrent_handle`], sharing the client's connection, and each request gets
+    /// its own output. Dispatchers without handles execute one request at a time.
+    /// In a closed loop, each client keeps this many requests going.
+    ///
+    /// [`Dispatcher::concurrent_handle`]: crate::client::Dispatcher::concurrent_handle
+    pub fn client_concurrency(mut self, client_concurrency: u32) -> Self {
+        self.client_concurrency = client_concurrency;
+        self
+    }
+
+    /// Gather inputs into batches of up to `max_size`, executing each batch with
+    /// [`Dispatcher::execute_batch`].
+    ///
+    /// After taking the first input of a batch, a client waits up to `max_wait` for the rest,
+    /// executing what it has when the time is up, along with any inputs that are already waiting,
+    /// so a `max_wait` of zero batches up whatever has queued. Each input still gets its own
+    /// output, recording the batch it was part of. A size of 1, the default, executes inputs one
+    /// at a time with [`Dispatcher::execute`].
+    ///
+    /// [`Dispatcher::execute_batch`]: crate::client::Dispatcher::execute_batch
+    /// [`Dispatcher::execute`]: crate::client::Dispatcher::execute
+    pub fn batch(mut self, max_size: u32, max_wait: Duration) -> Self {
+        self.batch_size = max_size;
+        self.batch_wait = max_wait;
+        self
+    }
+
+    /// Set what to do with inputs that are due when all of the clients are busy and the maximum
+    /// has been reached, blocking by default.
+    pub fn overload(mut self, overload: OverloadPolicy) -> Self {
+        self.overload = overload;
+        self
+    }
+
+    /// Set what to do when the run falls behind its schedule, bursting to catch up by default.
+    pub fn missed_ticks(mut self, missed_ticks: MissedTickBehavior) -> Self {
+        self.missed_ticks = missed_ticks;
+        self
+    }
+
+    /// Set how the run waits for each input to become due, sleeping by default.
+    ///
+    /// Use [`Pacing::Spin`] for evenly spaced inputs at rates above around a thousand per second.
+    pub fn pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Split the run across this many independent generator and client groups, 1 by default.
+    ///
+    /// Each shard generates its share of the rate with its own input generator, dispatcher
+    /// generator and clients, so a single generator loop doesn't limit the rate. The total, the
+    /// clients and warmup and cooldown in requests are split between the shards too, so there must
+    /// be at least as many clients and requests as shards.
+    ///
+    /// Run with [`generate_load_sharded`](crate::generate_load_sharded),
+    /// [`generate_load`](crate::generate_load) only runs a single shard.
+    pub fn shards(mut self, shards: u32) -> Self {
+        self.shards = shards;
+        self
+    }
+
+    /// Set whether each shard runs on its own thread, with its own single threaded runtime for its
+    /// generator and clients, disabled by default.
+    ///
+    /// Otherwise the shards run as tasks on the current runtime.
+    pub fn shard_threads(mut self, shard_threads: bool) -> Self {
+        self.shard_threads = shard_threads;
+        self
+    }
+
+    /// Control the run while it is going with a clone of `controller`, changing its rate, pausing
+    /// it or stopping it.
+    pub fn controller(mut self, controller: LoadController) -> Self {
+        self.controller = Some(controller);
+        self
+    }
+
+    /// Set what clients do after their dispatcher panics, replacing the dispatcher by default.
+    pub fn on_panic(mut self, on_panic: PanicPolicy) -> Self {
+        self.on_panic = on_panic;
+        self
+    }
+
+    /// Set how clients retry requests that fail, not retrying by default.
+    ///
+    /// Retries happen within a single output, which records the number of attempts. Latency is
+    /// measured over all of the attempts, including the backoffs between them.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Set the clock that the timestamps in outputs and the report are read from, the system
+    /// clock by default.
+    ///
+    /// Use a [`TokioClock`](crate::clock::TokioClock) with tokio's time paused for runs that
+    /// don't wait in real time and record the same timestamps every time.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Set the arrival process used to space out requests.
+    pub fn arrival(mut self, arrival: Arrival) -> Self {
+        self.arrival = arrival;
+        self
+    }
+
+    /// Set the seed for any randomness in the run, making it reproducible.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Mark the outputs at the start of the run as warmup.
+    pub fn warmup(mut self, warmup: PhaseLength) -> Self {
+        self.warmup = Some(warmup);
+        self
+    }
+
+    /// Mark the outputs at the end of the run as cooldown.
+    ///
+    /// The end of the run is only known from its stop conditions, so a cooldown in requests
+    /// needs a total and a cooldown in time needs a duration.
+    pub fn cooldown(mut self, cooldown: PhaseLength) -> Self {
+        self.cooldown = Some(cooldown);
+        self
+    }
+
+    /// Set whether SIGINT and SIGTERM stop the run gracefully, disabled by default so that a
+    /// library run doesn't take over the process's signals.
+    ///
+    /// The first signal stops generating new inputs, gives in-flight requests up to the shutdown
+    /// timeout to finish and then sends the collected outputs to the sink.
+    /// A second signal aborts the run without sending any more outputs.
+    ///
+    /// Once a run has listened for signals they no longer terminate the process by default, even
+    /// after the run has finished.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    /// Set how long in-flight requests get to finish after a signal before they are cancelled,
+    /// 30 seconds by default.
+    ///
+    /// A timeout too long to represent, such as `Duration::MAX`, never cancels them.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Set how many outputs can be waiting for the sink, 1024 by default.
+    ///
+    /// Outputs are streamed to the sink while the run is going. Once the buffer is full, clients
+    /// wait for the sink to catch up before starting their next request, so a slow sink limits
+    /// the achieved rate rather than using more memory.
+    pub fn output_buffer(mut self, output_buffer: usize) -> Self {
+        self.output_buffer = output_buffer;
+        self
+    }
+
+    /// Give up on requests that take longer than this, recording them as timed out.
+    ///
+    /// Dispatchers can override this for individual inputs with [`Dispatcher::timeout`].
+    ///
+    /// [`Dispatcher::timeout`]: crate::client::Dispatcher::timeout
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Check the values and build the options.
+    pub fn build(self) -> Result<LoadOptions, LoadOptionsError> {
+        self.profile
+            .validate()
+            .map_err(LoadOptionsError::InvalidProfile)?;
+        let max_rate = self.profile.max_rate();
+        if max_rate <= 0. {
+            return Err(LoadOptionsError::ZeroRate);
+        }
+        if max_rate > MAX_RATE {
+            return Err(LoadOptionsError::RateTooHigh(max_rate));
+        }
+        if self.total == Some(0) {
+            return Err(LoadOptionsError::ZeroTotal);
+        }
+        if self.duration == Some(Duration::ZERO) {
+            return Err(LoadOptionsError::ZeroDuration);
+        }
+        if let Some(max_clients) = self.max_clients {
+            if max_clients == 0 {
+                return Err(LoadOptionsError::ZeroMaxClients);
+            }
+            if max_clients < self.initial_clients {
+                return Err(LoadOptionsError::MaxClientsBelowInitial {
+                    initial_clients: self.initial_clients,
+                    max_clients,
+                });
+            }
+        }
+        if self.shards == 0 {
+            return Err(LoadOptionsError::ZeroShards);
+        }
+        // every shard needs its own share of the clients and requests
+        let clients = match &self.mode {
+            LoadMode::Open => None,
+            LoadMode::Closed { clients, .. } => Some(*clients),
+        };
+        if let Some(clients) = [clients, self.max_clients]
+            .into_iter()
+            .flatten()
+            .find(|clients| *clients < self.shards)
+        {
+            return Err(LoadOptionsError::FewerClientsThanShards {
+                clients,
+                shards: self.shards,
+            });
+        }
+        if let Some(total) = self.total.filter(|total| *total < self.shards as u64) {
+            return Err(LoadOptionsError::FewerRequestsThanShards {
+                total,
+                shards: self.shards,
+            });
+        }
+        if self.client_concurrency == 0 {
+            return Err(LoadOptionsError::ZeroClientConcurrency);
+        }
+        if self.batch_size == 0 {
+            return Err(LoadOptionsError::ZeroBatchSize);
+        }
+        if self.overload == (OverloadPolicy::Queue { depth: 0 }) {
+            return Err(LoadOptionsError::ZeroQueueDepth);
+        }
+        if let LoadMode::Closed {
+            clients,
+            think_time,
+        } = &self.mode
+        {
+            if *clients == 0 {
+                return Err(LoadOptionsError::ZeroClients);
+            }
+            think_time
+                .validate()
+                .map_err(LoadOptionsError::InvalidThinkTime)?;
+        }
+        match self.arrival {
+            Arrival::UniformJitter { jitter } if !(0. ..=1.).contains(&jitter) => {
+                return Err(LoadOptionsError::InvalidJitter(jitter));
+            }
+            Arrival::OnOff { on, .. } if on.is_zero() => {
+                return Err(LoadOptionsError::ZeroOnPeriod);
+            }
+            Arrival::OnOff { on, off } if on.checked_add(off).is_none() => {
+                return Err(LoadOptionsError::OnOffCycleTooLong);
+            }
+            _ => {}
+        }
+        if self.request_timeout == Some(Duration::ZERO) {
+            return Err(LoadOptionsError::ZeroRequestTimeout);
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(LoadOptionsError::ZeroAttempts);
+        }
+        if !(0. ..=1.).contains(&self.retry.jitter) {
+            return Err(LoadOptionsError::InvalidRetryJitter(self.retry.jitter));
+        }
+        if self.retry.initial_backoff > self.retry.max_backoff {
+            return Err(LoadOptionsError::InvalidBackoff {
+                initial: self.retry.initial_backoff,
+                max: self.retry.max_backoff,
+            });
+        }
+        if self.retry.multiplier.is_nan() || self.retry.multiplier < 1. {
+            return Err(LoadOptionsError::InvalidBackoffMultiplier(
+                self.retry.multiplier,
+            ));
+        }
+        if self.output_buffer == 0 {
+            return Err(LoadOptionsError::ZeroOutputBuffer);
+        }
+        match self.cooldown {
+            Some(PhaseLength::Requests(_)) if self.total.is_none() => {
+                return Err(LoadOptionsError::UnboundedCooldown);
+            }
+            Some(PhaseLength::Duration(_)) if self.duration.is_none() => {
+                return Err(LoadOptionsError::UnboundedCooldown);
+            }
+            _ => {}
+        }
+        Ok(LoadOptions {
+            mode: self.mode,
+            profile: self.profile,
+            initial_clients: self.initial_clients,
+            total: self.total,
+            duration: self.duration,
+            max_clients: self.max_clients,
+            arrival: self.arrival,
+            seed: self.seed,
+            warmup: self.warmup,
+            cooldown: self.cooldown,
+            handle_signals: self.handle_signals,
+            shutdown_timeout: self.shutdown_timeout,
+            output_buffer: self.output_buffer,
+            request_timeout: self.request_timeout,
+            overload: self.overload,
+            missed_ticks: self.missed_ticks,
+            pacing: self.pacing,
+            shards: self.shards,
+            shard_threads: self.shard_threads,
+            controller: self.controller,
+            clock: self.clock,
+            on_panic: self.on_panic,
+            retry: self.retry,
+            client_concurrency: self.client_concurrency,
+            batch_size: self.batch_size,
+            batch_wait: self.batch_wait,
+        })
+    }
+}
+
+/// Reasons that a set of [`LoadOptions`] is invalid.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadOptionsError {
+    /// The rate was never above zero.
+    ZeroRate,
+    /// The rate went too high to be paced.
+    RateTooHigh(f64),
+    /// The load profile couldn't be evaluated.
+    InvalidProfile(&'static str),
+    /// The total number of requests was zero.
+    ZeroTotal,
+    /// The duration was zero.
+    ZeroDuration,
+    /// The maximum number of clients was zero.
+    ZeroMaxClients,
+    /// The maximum number of clients was lower than the initial number.
+    MaxClientsBelowInitial {
+        initial_clients: u32,
+        max_clients: u32,
+    },
+    /// The uniform jitter fraction was outside of `0..=1`.
+    InvalidJitter(f64),
+    /// The on period of an on/off arrival process was zero.
+    ZeroOnPeriod,
+    /// The on and off periods of an on/off arrival process added up to more than can be
+    /// represented.
+    OnOffCycleTooLong,
+    /// A closed loop was requested with no clients.
+    ZeroClients,
+    /// The think time couldn't be sampled.
+    InvalidThinkTime(&'static str),
+    /// A cooldown was set without the matching stop condition to count back from.
+    UnboundedCooldown,
+    /// The output buffer had no space.
+    ZeroOutputBuffer,
+    /// The request timeout was zero.
+    ZeroRequestTimeout,
+    /// The overload queue had no space.
+    ZeroQueueDepth,
+    /// The run was split into zero shards.
+    ZeroShards,
+    /// Clients could execute no requests at once.
+    ZeroClientConcurrency,
+    /// Batches could hold no inputs.
+    ZeroBatchSize,
+    /// The retry policy allowed no attempts.
+    ZeroAttempts,
+    /// The retry jitter fraction was outside of `0..=1`.
+    InvalidRetryJitter(f64),
+    /// The initial backoff was longer than the maximum backoff.
+    InvalidBackoff { initial: Duration, max: Duration },
+    /// The backoff multiplier was below 1.
+    InvalidBackoffMultiplier(f64),
+    /// There weren't enough clients for each shard to have at least one.
+    FewerClientsThanShards { clients: u32, shards: u32 },
+    /// The total number of requests was lower than the number of shards.
+    FewerRequestsThanShards { total: u64, shards: u32 },
+    /// Options with more than one shard were given to [`generate_load`](crate::generate_load),
+    /// which only runs one.
+    ShardsNeedSharded(u32),
+}
+
+impl fmt::Display for LoadOptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroRate => write!(f, "rate must be above zero at some point in the run"),
+            Self::RateTooHigh(rate) => write!(
+                f,
+                "rate of {rate} requests per second is above the maximum of {MAX_RATE}"
+            ),
+            Self::InvalidProfile(reason) => write!(f, "invalid load profile: {reason}"),
+            Self::ZeroTotal => write!(f, "total must be at least 1 request"),
+            Self::ZeroDuration => write!(f, "duration must be greater than zero"),
+            Self::ZeroMaxClients => write!(f, "max clients must be at least 1"),
+            Self::MaxClientsBelowInitial {
+                initial_clients,
+                max_clients,
+            } => write!(
+                f,
+                "max clients ({max_clients}) is lower than initial clients ({initial_clients})"
+            ),
+            Self::InvalidJitter(jitter) => {
+                write!(f, "jitter of {jitter} is outside of the range 0 to 1")
+            }
+            Self::ZeroOnPeriod => write!(f, "on period of arrivals must be greater than zero"),
+            Self::OnOffCycleTooLong => {
+                write!(
+                    f,
+                    "on and off periods of arrivals are too long to represent"
+                )
+            }
+            Self::ZeroClients => write!(f, "closed loop must have at least 1 client"),
+            Self::InvalidThinkTime(reason) => write!(f, "invalid think time: {reason}"),
+            Self::UnboundedCooldown => write!(
+                f,
+                "cooldown needs a total for requests or a duration for time to count back from"
+            ),
+            Self::ZeroOutputBuffer => write!(f, "output buffer must hold at least 1 output"),
+            Self::ZeroRequestTimeout => write!(f, "request timeout must be greater than zero"),
+            Self::ZeroQueueDepth => write!(f, "overload queue must hold at least 1 input"),
+            Self::ZeroClientConcurrency => {
+                write!(f, "client concurrency must be at least 1 request")
+            }
+            Self::ZeroBatchSize => write!(f, "batch size must be at least 1 input"),
+            Self::ZeroAttempts => write!(f, "retry policy must allow at least 1 attempt"),
+            Self::InvalidRetryJitter(jitter) => {
+                write!(f, "retry jitter of {jitter} is outside of the range 0 to 1")
+            }
+            Self::InvalidBackoff { initial, max } => write!(
+                f,
+                "initial backoff of {initial:?} is longer than the maximum of {max:?}"
+            ),
+            Self::InvalidBackoffMultiplier(multiplier) => {
+                write!(f, "backoff multiplier of {multiplier} must be at least 1")
+            }
+            Self::ZeroShards => write!(f, "run must have at least 1 shard"),
+            Self::FewerClientsThanShards { clients, shards } => write!(
+                f,
+                "each of the {shards} shards needs a client but there are only {clients}"
+            ),
+            Self::FewerRequestsThanShards { total, shards } => write!(
+                f,
+                "each of the {shards} shards needs a request but the total is only {total}"
+            ),
+            Self::ShardsNeedSharded(shards) => write!(
+                f,
+                "run is split into {shards} shards, which needs generate_load_sharded"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadOptionsError {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_builder_defaults() {
+        let options = LoadOptions::builder().build().unwrap();
+        assert_eq!(options.profile(), &LoadProfile::Constant(100.));
+        assert_eq!(options.total(), None);
+        assert_eq!(options.duration(), None);
+        assert_eq!(options.initial_clients(), 0);
+        assert_eq!(options.max_clients(), None);
+        assert!(!options.handle_signals());
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_values() {
+        assert_eq!(
+            LoadOptions::builder().rate(0).build().unwrap_err(),
+            LoadOptionsError::ZeroRate
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .rate(MAX_RATE as u64 + 1)
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::RateTooHigh(MAX_RATE + 1.)
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .profile(LoadProfile::Sine {
+                    mean: 10.,
+                    amplitude: 5.,
+                    period: Duration::ZERO
+                })
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::InvalidProfile("sine period must not be zero")
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .closed_loop(
+                    1,
+                    ThinkTime::Uniform {
+                        min: Duration::from_secs(2),
+                        max: Duration::from_secs(1)
+                    }
+                )
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::InvalidThinkTime("uniform minimum must not be above the maximum")
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .closed_loop(
+                    1,
+                    ThinkTime::Exponential {
+                        mean: Duration::ZERO
+                    }
+                )
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::InvalidThinkTime("exponential mean must not be zero")
+        );
+        assert_eq!(
+            LoadOptions::builder().total(0).build().unwrap_err(),
+            LoadOptionsError::ZeroTotal
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .duration(Duration::ZERO)
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroDuration
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .max_clients(Some(0))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroMaxClients
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .initial_clients(4)
+                .max_clients(Some(2))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::MaxClientsBelowInitial {
+                initial_clients: 4,
+                max_clients: 2
+            }
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .arrival(Arrival::UniformJitter { jitter: 1.5 })
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::InvalidJitter(1.5)
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .duration(Duration::from_secs(10))
+                .cooldown(PhaseLength::Requests(10))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::UnboundedCooldown
+        );
+        assert_eq!(
+            LoadOptions::builder().output_buffer(0).build().unwrap_err(),
+            LoadOptionsError::ZeroOutputBuffer
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .request_timeout(Duration::ZERO)
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroRequestTimeout
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .overload(OverloadPolicy::Queue { depth: 0 })
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroQueueDepth
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .client_concurrency(0)
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroClientConcurrency
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .batch(0, Duration::from_millis(10))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroBatchSize
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .retry(RetryPolicy::new(0))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroAttempts
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .retry(RetryPolicy::new(3).jitter(2.))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::InvalidRetryJitter(2.)
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .retry(RetryPolicy::new(3).multiplier(0.5))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::InvalidBackoffMultiplier(0.5)
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .retry(RetryPolicy::new(3).backoff(Duration::from_secs(2), Duration::from_secs(1)))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::InvalidBackoff {
+                initial: Duration::from_secs(2),
+                max: Duration::from_secs(1)
+            }
+        );
+        assert_eq!(
+            LoadOptions::builder().shards(0).build().unwrap_err(),
+            LoadOptionsError::ZeroShards
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .shards(4)
+                .max_clients(Some(3))
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::FewerClientsThanShards {
+                clients: 3,
+                shards: 4
+            }
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .closed_loop(8, ThinkTime::None)
+                .max_clients(Some(3))
+                .shards(4)
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::FewerClientsThanShards {
+                clients: 3,
+                shards: 4
+            }
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .total(2)
+                .shards(4)
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::FewerRequestsThanShards {
+                total: 2,
+                shards: 4
+            }
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .arrival(Arrival::OnOff {
+                    on: Duration::ZERO,
+                    off: Duration::from_secs(1)
+                })
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::ZeroOnPeriod
+        );
+        assert_eq!(
+            LoadOptions::builder()
+                .arrival(Arrival::OnOff {
+                    on: Duration::from_secs(1),
+                    off: Duration::MAX
+                })
+                .build()
+                .unwrap_err(),
+            LoadOptionsError::OnOffCycleTooLong
+        );
+    }
+
+    #[test]
+    fn test_shard_splits_totals() {
+        let options = LoadOptions::builder()
+            .total(10)
+            .initial_clients(5)
+            .max_clients(Some(7))
+            .warmup(PhaseLength::Requests(3))
+            .shards(3)
+            .build()
+            .unwrap();
+        let shards: Vec<_> = (0..3).map(|shard| options.shard(shard, 3)).collect();
+        let totals: Vec<_> = shards.iter().map(|s| s.total().unwrap()).collect();
+        assert_eq!(totals, [4, 3, 3]);
+        let initial: Vec<_> = shards.iter().map(|s| s.initial_clients()).collect();
+        assert_eq!(initial, [2, 2, 1]);
+        let max: Vec<_> = shards.iter().map(|s| s.max_clients().unwrap()).collect();
+        assert_eq!(max, [3, 2, 2]);
+        let warmup: Vec<_> = shards.iter().map(|s| s.warmup().unwrap()).collect();
+        assert_eq!(
+            warmup,
+            [
+                PhaseLength::Requests(1),
+                PhaseLength::Requests(1),
+                PhaseLength::Requests(1)
+            ]
+        );
+    }
+}
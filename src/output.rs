@@ -14,28 +14,102 @@ pub struct Output<D> {
 /// Core data captured by loadbench.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OutputCore {
+    /// Time this execution was scheduled to start by the load generator, or the start time if it
+    /// had no schedule, as in closed-loop runs.
+    ///
+    /// Measuring latency from this, rather than `start_ns`, includes any time the request spent
+    /// waiting for a free client and so avoids coordinated omission.
+    pub scheduled_ns: i64,
     /// Start time of this execution.
     pub start_ns: i64,
     /// End time of this execution.
     pub end_ns: i64,
+    /// How the execution ended.
+    pub outcome: Outcome,
     /// An error that may have occurred.
     pub error: Option<String>,
+    /// Number of attempts made at the request, more than 1 if it was retried.
+    pub attempts: u32,
+    /// Latency of each attempt, if the retry policy records them.
+    ///
+    /// Written as a `;` separated list so that it fits in a single csv column.
+    #[serde(with = "attempt_latencies")]
+    pub attempt_latencies_ns: Vec<i64>,
+    /// Number of inputs in the batch that this execution was part of, 1 if it wasn't batched.
+    pub batch_size: u32,
+    /// The batch that this execution was part of, unique within the run, if it was batched.
+    pub batch_id: Option<u64>,
+    /// The session that the input was part of, if it had one.
+    pub session: Option<u64>,
+    /// The scenario that the execution was part of, if the run had scenarios.
+    pub scenario: Option<String>,
     /// The client that ran the execution.
     pub client: u32,
     /// The iteration of the client that this execution became.
     pub iteration: u32,
+    /// The phase of the run that this execution was part of.
+    pub phase: Phase,
+}
+
+/// Phases of a run, so that warmup and cooldown can be told apart from the measured requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Getting the system under test up to speed.
+    Warmup,
+    /// The steady state that should be measured.
+    #[default]
+    Measure,
+    /// Winding down at the end of the run.
+    Cooldown,
+}
+
+/// How an execution ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The dispatcher returned successfully.
+    #[default]
+    Success,
+    /// The dispatcher returned an error, recorded in `error`.
+    Error,
+    /// The dispatcher didn't return within the request timeout.
+    Timeout,
+    /// The input was never sent as all of the clients were busy, so it has no client.
+    Dropped,
 }
 
 impl<D: Default> Output<D> {
-    pub fn start(client: u32, iteration: u32) -> Self {
-        let now = chrono::Utc::now();
+    /// Start recording an execution, which was scheduled to start at `scheduled_ns` if it had a
+    /// schedule, otherwise it is treated as scheduled for now.
+    pub fn start(client: u32, iteration: u32, scheduled_ns: Option<i64>) -> Self {
+        Self::start_at(
+            client,
+            iteration,
+            scheduled_ns,
+            chrono::Utc::now().timestamp_nanos(),
+        )
+    }
+
+    /// Start recording an execution like [`Output::start`], with the start time given rather
+    /// than read from the system clock.
+    pub fn start_at(client: u32, iteration: u32, scheduled_ns: Option<i64>, now_ns: i64) -> Self {
         Self {
             core: OutputCore {
                 client,
                 iteration,
-                start_ns: now.timestamp_nanos(),
-                end_ns: now.timestamp_nanos(),
+                scheduled_ns: scheduled_ns.unwrap_or(now_ns),
+                start_ns: now_ns,
+                end_ns: now_ns,
+                outcome: Outcome::default(),
                 error: None,
+                attempts: 1,
+                attempt_latencies_ns: Vec::new(),
+                batch_size: 1,
+                batch_id: None,
+                session: None,
+                scenario: None,
+                phase: Phase::default(),
             },
             custom: D::default(),
         }
@@ -43,17 +117,58 @@ impl<D: Default> Output<D> {
 }
 
 impl<D> Output<D> {
+    /// Record the end of the execution, reading the time from the system clock.
     pub fn stop(&mut self) {
-        self.core.end_ns = chrono::Utc::now().timestamp_nanos();
+        self.stop_at(chrono::Utc::now().timestamp_nanos());
     }
 
+    /// Record the end of the execution at the given time rather than reading the system clock.
+    pub fn stop_at(&mut self, end_ns: i64) {
+        self.core.end_ns = end_ns;
+    }
+
+    /// Record that the execution ended with an error, reading the time from the system clock.
     pub fn error(&mut self, error: String) {
+        self.error_at(error, chrono::Utc::now().timestamp_nanos());
+    }
+
+    /// Record the error like [`Output::error`], with the end time given rather than read from the
+    /// system clock.
+    pub fn error_at(&mut self, error: String, end_ns: i64) {
+        self.core.outcome = Outcome::Error;
         self.core.error = Some(error);
-        self.core.end_ns = chrono::Utc::now().timestamp_nanos();
+        self.core.end_ns = end_ns;
+    }
+
+    /// Record that the execution was given up on after the request timeout, reading the time
+    /// from the system clock.
+    pub fn timeout(&mut self) {
+        self.timeout_at(chrono::Utc::now().timestamp_nanos());
+    }
+
+    /// Record the timeout like [`Output::timeout`], with the end time given rather than read from
+    /// the system clock.
+    pub fn timeout_at(&mut self, end_ns: i64) {
+        self.core.outcome = Outcome::Timeout;
+        self.core.end_ns = end_ns;
+    }
+
+    /// Record that the input was dropped without being executed.
+    pub fn dropped(&mut self) {
+        self.core.outcome = Outcome::Dropped;
+        self.core.end_ns = self.core.start_ns;
     }
 
     pub fn is_error(&self) -> bool {
-        self.core.error.is_some()
+        self.core.outcome == Outcome::Error
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        self.core.outcome == Outcome::Timeout
+    }
+
+    pub fn is_dropped(&self) -> bool {
+        self.core.outcome == Outcome::Dropped
     }
 
     pub fn data_mut(&mut self) -> &mut D {
@@ -61,10 +176,26 @@ impl<D> Output<D> {
     }
 }
 
-impl<D> Drop for Output<D> {
-    fn drop(&mut self) {
-        if self.core.end_ns == self.core.start_ns {
-            self.stop()
-        }
+/// Writes the attempt latencies as a single `;` separated string.
+mod attempt_latencies {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        latencies: &[i64],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let latencies: Vec<_> = latencies.iter().map(i64::to_string).collect();
+        serializer.serialize_str(&latencies.join(";"))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<i64>, D::Error> {
+        let latencies = String::deserialize(deserializer)?;
+        latencies
+            .split(';')
+            .filter(|latency| !latency.is_empty())
+            .map(|latency| latency.parse().map_err(D::Error::custom))
+            .collect()
     }
 }
@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use serde::Serialize;
 
-use crate::Output;
+use crate::{Output, Phase};
 
 /// A sink for outputs.
 #[async_trait]
@@ -18,11 +18,21 @@ impl<O: Send + 'static> OutputSink<O> for NoOpOutputSink {
 }
 
 /// Produce some stats from the outputs.
+///
+/// Outputs from the warmup and cooldown phases are left out of the stats unless included with
+/// [`StatsOutputSink::include_warmup`] and [`StatsOutputSink::include_cooldown`].
 #[derive(Default, Debug)]
 pub struct StatsOutputSink {
+    include_warmup: bool,
+    include_cooldown: bool,
+    warmup_count: u64,
+    cooldown_count: u64,
     error_count: u64,
+    timeout_count: u64,
+    dropped_count: u64,
     success_count: u64,
     latency_ns: Vec<i64>,
+    scheduled_latency_ns: Vec<i64>,
     start_ns: i64,
     end_ns: i64,
 }
@@ -30,14 +40,40 @@ pub struct StatsOutputSink {
 #[async_trait]
 impl<O: Send + 'static> OutputSink<O> for StatsOutputSink {
     async fn send(&mut self, output: Output<O>) {
+        match output.core.phase {
+            Phase::Warmup => {
+                self.warmup_count += 1;
+                if !self.include_warmup {
+                    return;
+                }
+            }
+            Phase::Cooldown => {
+                self.cooldown_count += 1;
+                if !self.include_cooldown {
+                    return;
+                }
+            }
+            Phase::Measure => {}
+        }
+
+        if output.is_dropped() {
+            // never sent, so there's no latency to record
+            self.dropped_count += 1;
+            return;
+        }
+
         if output.is_error() {
             self.error_count += 1;
+        } else if output.is_timeout() {
+            self.timeout_count += 1;
         } else {
             self.success_count += 1;
         }
 
         let latency_ns = output.core.end_ns - output.core.start_ns;
         self.latency_ns.push(latency_ns);
+        let scheduled_latency_ns = output.core.end_ns - output.core.scheduled_ns;
+        self.scheduled_latency_ns.push(scheduled_latency_ns);
 
         if self.start_ns == 0 {
             self.start_ns = output.core.start_ns;
@@ -48,12 +84,38 @@ impl<O: Send + 'static> OutputSink<O> for StatsOutputSink {
 }
 
 impl StatsOutputSink {
+    /// Include outputs from the warmup phase in the stats.
+    pub fn include_warmup(mut self, include: bool) -> Self {
+        self.include_warmup = include;
+        self
+    }
+
+    /// Include outputs from the cooldown phase in the stats.
+    pub fn include_cooldown(mut self, include: bool) -> Self {
+        self.include_cooldown = include;
+        self
+    }
+
     /// Print stats.
     pub fn summary(&self) {
-        let total = self.success_count + self.error_count;
+        let included = |include| if include { "included" } else { "excluded" };
+        println!(
+            "    Warmup requests: {} ({})",
+            self.warmup_count,
+            included(self.include_warmup)
+        );
+        println!(
+            "  Cooldown requests: {} ({})",
+            self.cooldown_count,
+            included(self.include_cooldown)
+        );
+
+        let total = self.success_count + self.error_count + self.timeout_count;
         println!("     Total requests: {}", total);
         println!("Successful requests: {}", self.success_count);
         println!(" Erroneous requests: {}", self.error_count);
+        println!(" Timed out requests: {}", self.timeout_count);
+        println!("   Dropped requests: {}", self.dropped_count);
 
         let duration_ns = self.end_ns - self.start_ns;
         let duration_s = duration_ns as f64 / 1_000_000_000.;
@@ -61,25 +123,39 @@ impl StatsOutputSink {
         let tp = total as f64 / duration_s;
         let tp_success = self.success_count as f64 / duration_s;
         let tp_error = self.error_count as f64 / duration_s;
+        let tp_timeout = self.timeout_count as f64 / duration_s;
 
         println!("     Total throughput (req/s): {}", tp);
         println!("Successful throughput (req/s): {}", tp_success);
         println!(" Erroneous throughput (req/s): {}", tp_error);
+        println!(" Timed out throughput (req/s): {}", tp_timeout);
 
-        let mut latencies = self.latency_ns.clone();
-        latencies.sort_unstable();
-
-        let percentile = |latencies: &[i64], percentile: f64| {
-            let index = (latencies.len() - 1) as f64 * percentile;
-            latencies[index as usize]
-        };
+        println!("Latency from actual start:");
+        print_percentiles(&self.latency_ns);
+        println!("Latency from scheduled start:");
+        print_percentiles(&self.scheduled_latency_ns);
+    }
+}
 
-        println!("  0% latency (ns): {}", latencies.first().unwrap());
-        println!(" 50% latency (ns): {}", percentile(&latencies, 0.5));
-        println!(" 90% latency (ns): {}", percentile(&latencies, 0.9));
-        println!(" 99% latency (ns): {}", percentile(&latencies, 0.99));
-        println!("100% latency (ns): {}", latencies.last().unwrap());
+fn print_percentiles(latencies: &[i64]) {
+    if latencies.is_empty() {
+        // e.g. when a run was aborted before any outputs were sent
+        println!("  No latencies recorded");
+        return;
     }
+    let mut latencies = latencies.to_vec();
+    latencies.sort_unstable();
+
+    let percentile = |latencies: &[i64], percentile: f64| {
+        let index = (latencies.len() - 1) as f64 * percentile;
+        latencies[index as usize]
+    };
+
+    println!("  0% latency (ns): {}", latencies.first().unwrap());
+    println!(" 50% latency (ns): {}", percentile(&latencies, 0.5));
+    println!(" 90% latency (ns): {}", percentile(&latencies, 0.9));
+    println!(" 99% latency (ns): {}", percentile(&latencies, 0.99));
+    println!("100% latency (ns): {}", latencies.last().unwrap());
 }
 
 /// Write outputs to a csv file.
@@ -98,15 +174,44 @@ impl<O: Serialize + Send + 'static, W: std::io::Write + Send> OutputSink<O> for
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn test_stats_output_sink_excludes_warmup() {
+        let mut sink = StatsOutputSink::default();
+        for phase in [Phase::Warmup, Phase::Measure, Phase::Cooldown] {
+            let mut output = Output::<()>::start(0, 0, None);
+            output.core.phase = phase;
+            output.stop();
+            sink.send(output).await;
+        }
+        assert_eq!(sink.warmup_count, 1);
+        assert_eq!(sink.cooldown_count, 1);
+        assert_eq!(sink.success_count, 1);
+
+        let mut sink = StatsOutputSink::default().include_warmup(true);
+        let mut output = Output::<()>::start(0, 0, None);
+        output.core.phase = Phase::Warmup;
+        sink.send(output).await;
+        assert_eq!(sink.success_count, 1);
+    }
+
     #[tokio::test]
     async fn test_csv_output_sink() {
         let output = Output {
             core: crate::OutputCore {
+                scheduled_ns: 0,
                 start_ns: 0,
                 end_ns: 0,
+                outcome: crate::Outcome::Success,
                 error: None,
+                attempts: 1,
+                attempt_latencies_ns: Vec::new(),
+                batch_size: 1,
+                batch_id: None,
+                session: None,
+                scenario: None,
                 client: 0,
                 iteration: 0,
+                phase: Phase::Measure,
             },
             custom: (),
         };
@@ -0,0 +1,298 @@
+//! Load profiles give the target rate over the course of a run.
+
+use std::{f64::consts::TAU, time::Duration};
+
+/// The target rate, in requests per second, as a function of the time since the run started.
+///
+/// Profiles can be composed with [`LoadProfile::then`] to run one after another, and with
+/// [`LoadProfile::Sum`] to add them together.
+/// Negative rates are treated as zero.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadProfile {
+    /// A fixed rate.
+    Constant(f64),
+    /// Move linearly from one rate to another over a period, holding the final rate after.
+    Ramp { from: f64, to: f64, over: Duration },
+    /// Start at a rate and change it by `step` every `every`, for `steps` steps, holding the final
+    /// rate after.
+    Steps {
+        start: f64,
+        step: f64,
+        every: Duration,
+        steps: u32,
+    },
+    /// Oscillate around `mean` by `amplitude`, starting at the mean and rising first.
+    Sine {
+        mean: f64,
+        amplitude: f64,
+        period: Duration,
+    },
+    /// Hold each rate for its duration in turn, holding the final rate after.
+    Piecewise(Vec<(Duration, f64)>),
+    /// Run the first profile for `duration` and then the next, which sees time starting from zero.
+    Then {
+        first: Box<LoadProfile>,
+        duration: Duration,
+        next: Box<LoadProfile>,
+    },
+    /// The sum of the rates of all of the profiles.
+    Sum(Vec<LoadProfile>),
+}
+
+impl LoadProfile {
+    /// Run this profile for `duration` and then switch to `next`.
+    ///
+    /// When chained, each duration applies to the profile just before it, so
+    /// `a.then(d1, b).then(d2, c)` runs `a` for `d1`, `b` for `d2` and then `c`.
+    pub fn then(self, duration: Duration, next: LoadProfile) -> Self {
+        match self {
+            Self::Then {
+                first,
+                duration: first_duration,
+                next: middle,
+            } => Self::Then {
+                first,
+                duration: first_duration,
+                next: Box::new(middle.then(duration, next)),
+            },
+            profile => Self::Then {
+                first: Box::new(profile),
+                duration,
+                next: Box::new(next),
+            },
+        }
+    }
+
+    /// The target rate at `elapsed` time since the start of the run.
+    pub fn rate_at(&self, elapsed: Duration) -> f64 {
+        let rate = match self {
+            Self::Constant(rate) => *rate,
+            Self::Ramp { from, to, over } => {
+                if elapsed >= *over {
+                    *to
+                } else {
+                    let progress = elapsed.as_secs_f64() / over.as_secs_f64();
+                    from + (to - from) * progress
+                }
+            }
+            Self::Steps {
+                start,
+                step,
+                every,
+                steps,
+            } => {
+                let taken = if every.is_zero() {
+                    *steps
+                } else {
+                    (elapsed.as_nanos() / every.as_nanos()).min(*steps as u128) as u32
+                };
+                start + step * taken as f64
+            }
+            Self::Sine {
+                mean,
+                amplitude,
+                period,
+            } => {
+                let phase = elapsed.as_secs_f64() / period.as_secs_f64();
+                mean + amplitude * (TAU * phase).sin()
+            }
+            Self::Piecewise(pieces) => {
+                let mut remaining = elapsed;
+                let mut rate = 0.;
+                for (duration, piece_rate) in pieces {
+                    rate = *piece_rate;
+                    if remaining < *duration {
+                        break;
+                    }
+                    remaining -= *duration;
+                }
+                rate
+            }
+            Self::Then {
+                first,
+                duration,
+                next,
+            } => {
+                if elapsed < *duration {
+                    first.rate_at(elapsed)
+                } else {
+                    next.rate_at(elapsed - *duration)
+                }
+            }
+            Self::Sum(profiles) => profiles.iter().map(|p| p.rate_at(elapsed)).sum(),
+        };
+        rate.max(0.)
+    }
+
+    /// The highest rate that this profile can reach.
+    pub fn max_rate(&self) -> f64 {
+        match self {
+            Self::Constant(rate) => *rate,
+            Self::Ramp { from, to, .. } => from.max(*to),
+            Self::Steps {
+                start, step, steps, ..
+            } => start.max(start + step * *steps as f64),
+            Self::Sine {
+                mean, amplitude, ..
+            } => mean + amplitude.abs(),
+            Self::Piecewise(pieces) => pieces
+                .iter()
+                .map(|(_, rate)| *rate)
+                .fold(f64::NEG_INFINITY, f64::max),
+            Self::Then { first, next, .. } => first.max_rate().max(next.max_rate()),
+            Self::Sum(profiles) => profiles.iter().map(|p| p.max_rate().max(0.)).sum(),
+        }
+    }
+
+    /// When the profile settles on a rate of zero that it holds for good, if it does.
+    pub(crate) fn ends_at(&self) -> Option<Duration> {
+        self.settles_at()
+            .filter(|settled| self.rate_at(*settled) <= 0.)
+    }
+
+    /// When the profile settles on the rate that it holds for good, if it does.
+    fn settles_at(&self) -> Option<Duration> {
+        match self {
+            Self::Constant(_) => Some(Duration::ZERO),
+            Self::Ramp { over, .. } => Some(*over),
+            Self::Steps { every, steps, .. } => Some(every.saturating_mul(*steps)),
+            Self::Sine { .. } => None,
+            // the last piece is held from when it starts
+            Self::Piecewise(pieces) => Some(
+                pieces
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .map(|(duration, _)| *duration)
+                    .fold(Duration::ZERO, Duration::saturating_add),
+            ),
+            Self::Then { duration, next, .. } => next
+                .settles_at()
+                .map(|settled| duration.saturating_add(settled)),
+            Self::Sum(profiles) => profiles
+                .iter()
+                .map(LoadProfile::settles_at)
+                .try_fold(Duration::ZERO, |latest, settled| Some(latest.max(settled?))),
+        }
+    }
+
+    /// Check that the profile can be evaluated, returning a description of the problem if not.
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            Self::Constant(rate) if !rate.is_finite() => Err("rate must be finite"),
+            Self::Ramp { from, to, .. } if !from.is_finite() || !to.is_finite() => {
+                Err("ramp rates must be finite")
+            }
+            Self::Steps { start, step, .. } if !start.is_finite() || !step.is_finite() => {
+                Err("step rates must be finite")
+            }
+            Self::Sine { period, .. } if period.is_zero() => Err("sine period must not be zero"),
+            Self::Sine {
+                mean, amplitude, ..
+            } if !mean.is_finite() || !amplitude.is_finite() => Err("sine rates must be finite"),
+            Self::Piecewise(pieces) if pieces.is_empty() => {
+                Err("piecewise profile must have at least one piece")
+            }
+            Self::Piecewise(pieces) if pieces.iter().any(|(_, rate)| !rate.is_finite()) => {
+                Err("piecewise rates must be finite")
+            }
+            Self::Then { first, next, .. } => first.validate().and_then(|()| next.validate()),
+            Self::Sum(profiles) if profiles.is_empty() => {
+                Err("sum profile must have at least one profile")
+            }
+            Self::Sum(profiles) => profiles.iter().try_for_each(LoadProfile::validate),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_ramp_then_hold_then_step_down() {
+        let profile = LoadProfile::Ramp {
+            from: 100.,
+            to: 10_000.,
+            over: secs(300),
+        }
+        .then(secs(600), LoadProfile::Constant(1_000.));
+
+        assert_eq!(profile.rate_at(secs(0)), 100.);
+        assert_eq!(profile.rate_at(secs(150)), 5_050.);
+        assert_eq!(profile.rate_at(secs(450)), 10_000.);
+        assert_eq!(profile.rate_at(secs(600)), 1_000.);
+        assert_eq!(profile.max_rate(), 10_000.);
+    }
+
+    #[test]
+    fn test_steps_and_piecewise() {
+        let steps = LoadProfile::Steps {
+            start: 10.,
+            step: 10.,
+            every: secs(5),
+            steps: 2,
+        };
+        assert_eq!(steps.rate_at(secs(4)), 10.);
+        assert_eq!(steps.rate_at(secs(5)), 20.);
+        assert_eq!(steps.rate_at(secs(100)), 30.);
+
+        let piecewise = LoadProfile::Piecewise(vec![(secs(1), 5.), (secs(1), 50.)]);
+        assert_eq!(piecewise.rate_at(secs(0)), 5.);
+        assert_eq!(piecewise.rate_at(secs(1)), 50.);
+        assert_eq!(piecewise.rate_at(secs(10)), 50.);
+    }
+
+    #[test]
+    fn test_sine_and_sum() {
+        let profile = LoadProfile::Sum(vec![
+            LoadProfile::Constant(10.),
+            LoadProfile::Sine {
+                mean: 100.,
+                amplitude: 50.,
+                period: secs(4),
+            },
+        ]);
+        assert!((profile.rate_at(secs(1)) - 160.).abs() < 1e-9);
+        assert!((profile.rate_at(secs(3)) - 60.).abs() < 1e-9);
+        assert_eq!(profile.max_rate(), 160.);
+    }
+
+    #[test]
+    fn test_ends_at() {
+        let ramp_down = LoadProfile::Ramp {
+            from: 100.,
+            to: 0.,
+            over: secs(10),
+        };
+        assert_eq!(ramp_down.ends_at(), Some(secs(10)));
+        assert_eq!(LoadProfile::Constant(0.).ends_at(), Some(secs(0)));
+        assert_eq!(LoadProfile::Constant(10.).ends_at(), None);
+
+        let piecewise = LoadProfile::Piecewise(vec![(secs(1), 50.), (secs(2), 0.), (secs(1), 0.)]);
+        assert_eq!(piecewise.ends_at(), Some(secs(3)));
+        let steps = LoadProfile::Steps {
+            start: 30.,
+            step: -10.,
+            every: secs(5),
+            steps: 3,
+        };
+        assert_eq!(steps.ends_at(), Some(secs(15)));
+
+        let chained = LoadProfile::Constant(10.).then(secs(5), ramp_down.clone());
+        assert_eq!(chained.ends_at(), Some(secs(15)));
+        let sum = LoadProfile::Sum(vec![ramp_down.clone(), LoadProfile::Constant(0.)]);
+        assert_eq!(sum.ends_at(), Some(secs(10)));
+        let sine = LoadProfile::Sine {
+            mean: 0.,
+            amplitude: 10.,
+            period: secs(4),
+        };
+        assert_eq!(LoadProfile::Sum(vec![ramp_down, sine]).ends_at(), None);
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::StopReason;
+
+/// A summary of a run from the load generator's side, to archive alongside the outputs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunReport {
+    /// Inputs taken from the input generator.
+    pub inputs_generated: u64,
+    /// Inputs handed to a client.
+    pub inputs_sent: u64,
+    /// Inputs dropped by the overload policy.
+    pub inputs_dropped: u64,
+    /// Clients spawned over the course of the run.
+    pub clients_spawned: u32,
+    /// Highest number of requests that were executing at once.
+    pub peak_in_flight: u32,
+    /// Failed requests that the retry policy would have retried, but that the dispatcher gave
+    /// no input to retry with.
+    pub retries_skipped: u64,
+    /// Time the run started generating load.
+    pub start_ns: i64,
+    /// Time the last output was sent to the sink.
+    pub end_ns: i64,
+    /// The condition that ended the run.
+    pub stop_reason: StopReason,
+    /// How closely the run kept to its schedule, only for open-loop runs.
+    pub schedule: Option<ScheduleReport>,
+    /// Client tasks and shards that couldn't be joined, so their later outputs may be missing, and
+    /// clients that couldn't be started.
+    pub failed_joins: Vec<FailedJoin>,
+}
+
+/// The report of one of the scenarios run together by
+/// [`generate_scenarios`](crate::generate_scenarios).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScenarioReport {
+    /// The name of the scenario.
+    pub scenario: String,
+    /// The report of the scenario's run, or why it failed, such as from a panic.
+    pub report: Result<RunReport, String>,
+}
+
+/// A client task or shard that couldn't be joined, or a client or shard that couldn't be started.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedJoin {
+    /// The shard that the task belonged to.
+    pub shard: u32,
+    /// The client that the task was running, or `None` for the shard's generator loop.
+    pub client: Option<u32>,
+    /// Why the join failed.
+    pub error: String,
+}
+
+impl FailedJoin {
+    /// A shard whose generator loop couldn't be joined or started.
+    pub(crate) fn shard(shard: u32, error: String) -> Self {
+        Self {
+            shard,
+            client: None,
+            error,
+        }
+    }
+}
+
+/// Counters updated by the generator and clients while the run is going.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) generated: AtomicU64,
+    pub(crate) sent: AtomicU64,
+    pub(crate) dropped: AtomicU64,
+    /// Clients spawned so far, which also hands out their IDs.
+    pub(crate) clients: AtomicU32,
+    pub(crate) in_flight: AtomicU32,
+    pub(crate) peak_in_flight: AtomicU32,
+    /// Batches gathered so far, which hands out their IDs.
+    pub(crate) batches: AtomicU64,
+    pub(crate) retries_skipped: AtomicU64,
+}
+
+impl Counters {
+    /// Mark a request as starting to execute.
+    pub(crate) fn start_request(&self) {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+    }
+
+    /// Mark a request as having finished executing.
+    pub(crate) fn finish_request(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How closely an open-loop run kept to its schedule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleReport {
+    /// How late each input was handed to a client, compared to when it was scheduled.
+    pub lag: Histogram,
+    /// How far each gap between inputs being handed to clients was from the scheduled gap.
+    pub jitter: Histogram,
+    /// Average rate that the load profile asked for while load was being generated.
+    pub target_rate: f64,
+    /// Average rate that inputs were handed to clients while load was being generated.
+    pub achieved_rate: f64,
+    /// Inputs that were due more than a millisecond before the generator got to them.
+    pub missed_ticks: u64,
+}
+
+impl ScheduleReport {
+    /// Add in the report of another shard that ran alongside this one.
+    pub(crate) fn merge(&mut self, other: &ScheduleReport) {
+        self.lag.merge(&other.lag);
+        self.jitter.merge(&other.jitter);
+        self.target_rate += other.target_rate;
+        self.achieved_rate += other.achieved_rate;
+        self.missed_ticks += other.missed_ticks;
+    }
+}
+
+/// Number of buckets in a [`Histogram`], the last of which is unbounded.
+const BUCKETS: usize = 32;
+
+/// Counts of durations in buckets that double in width, from under a microsecond to over half an
+/// hour.
+///
+/// Bucket `i` holds durations below `2^i` microseconds that didn't fit in the bucket before.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum_ns: u64,
+    max_ns: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            sum_ns: 0,
+            max_ns: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        let ns = duration.as_nanos() as u64;
+        self.count += 1;
+        self.sum_ns = self.sum_ns.saturating_add(ns);
+        self.max_ns = self.max_ns.max(ns);
+    }
+
+    /// Add the durations recorded in another histogram.
+    pub(crate) fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum_ns = self.sum_ns.saturating_add(other.sum_ns);
+        self.max_ns = self.max_ns.max(other.max_ns);
+    }
+
+    /// Number of durations recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Counts in each bucket, along with the exclusive upper bound of the bucket, or `None` for the
+    /// last bucket.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, count)| {
+            let upper = (i < BUCKETS - 1).then(|| Duration::from_micros(1 << i));
+            (upper, *count)
+        })
+    }
+
+    /// Mean of the recorded durations.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.sum_ns / self.count)
+    }
+
+    /// Longest recorded duration.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_ns)
+    }
+
+    /// Upper bound on the given percentile, from `0.` to `1.`, limited to the longest duration.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let target = (self.count as f64 * percentile).ceil() as u64;
+        let mut seen = 0;
+        for (upper, count) in self.buckets() {
+            seen += count;
+            if seen >= target.max(1) {
+                return upper.map_or(self.max(), |upper| upper.min(self.max()));
+            }
+        }
+        self.max()
+    }
+}
+
+/// Keeps track of when inputs were scheduled and sent, for the [`ScheduleReport`].
+#[derive(Debug, Default)]
+pub(crate) struct ScheduleStats {
+    lag: Histogram,
+    jitter: Histogram,
+    sent: u64,
+    missed_ticks: u64,
+    /// When the last input was scheduled and sent.
+    last: Option<(Instant, Instant)>,
+}
+
+impl ScheduleStats {
+    /// Record an input scheduled at `scheduled` being handed to a client at `sent`.
+    pub(crate) fn record_send(&mut self, scheduled: Instant, sent: Instant) {
+        self.lag.record(sent.saturating_duration_since(scheduled));
+        if let Some((last_scheduled, last_sent)) = self.last {
+            let scheduled_gap = scheduled.saturating_duration_since(last_scheduled);
+            let sent_gap = sent.saturating_duration_since(last_sent);
+            self.jitter
+                .record(sent_gap.max(scheduled_gap) - sent_gap.min(scheduled_gap));
+        }
+        self.last = Some((scheduled, sent));
+        self.sent += 1;
+    }
+
+    pub(crate) fn record_missed_tick(&mut self) {
+        self.missed_ticks += 1;
+    }
+
+    /// Finish the report, for a generation window of `elapsed` over which the profile expected
+    /// `expected` inputs.
+    pub(crate) fn report(self, expected: f64, elapsed: Duration) -> ScheduleReport {
+        let secs = elapsed.as_secs_f64();
+        let rate = |count: f64| if secs > 0. { count / secs } else { 0. };
+        ScheduleReport {
+            lag: self.lag,
+            jitter: self.jitter,
+            target_rate: rate(expected),
+            achieved_rate: rate(self.sent as f64),
+            missed_ticks: self.missed_ticks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut histogram = Histogram::default();
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(1));
+
+        assert_eq!(histogram.count(), 101);
+        assert_eq!(histogram.percentile(0.5), Duration::from_micros(64));
+        assert_eq!(histogram.percentile(0.9), Duration::from_micros(128));
+        assert_eq!(histogram.percentile(1.), Duration::from_secs(1));
+        assert_eq!(histogram.max(), Duration::from_secs(1));
+        assert_eq!(
+            histogram.buckets().map(|(_, count)| count).sum::<u64>(),
+            101
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// How clients retry requests that fail, within a single output.
+///
+/// Each retry waits for a backoff that starts at the initial backoff and grows by the multiplier
+/// after every attempt, up to the maximum backoff. Jitter takes up to that fraction off each
+/// backoff at random, so that clients that failed together don't retry together.
+///
+/// Retrying needs a copy of the input from [`Dispatcher::retry_input`], which by default gives
+/// none. Inputs that the dispatcher doesn't copy are only attempted once, with the requests that
+/// would have been retried counted in [`RunReport::retries_skipped`].
+///
+/// The default makes a single attempt, without retrying.
+///
+/// [`Dispatcher::retry_input`]: crate::client::Dispatcher::retry_input
+/// [`RunReport::retries_skipped`]: crate::RunReport::retries_skipped
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+    pub(crate) multiplier: f64,
+    pub(crate) jitter: f64,
+    retryable: Arc<dyn Fn(&str) -> bool + Send + Sync>,
+    pub(crate) retry_timeouts: bool,
+    pub(crate) record_attempts: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("jitter", &self.jitter)
+            .field("retry_timeouts", &self.retry_timeouts)
+            .field("record_attempts", &self.record_attempts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryPolicy {
+    /// Make up to `max_attempts` attempts at each request, retrying any error after a backoff
+    /// starting at 10 milliseconds, doubling up to 1 second, with half jitter.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.,
+            jitter: 0.5,
+            retryable: Arc::new(|_| true),
+            retry_timeouts: false,
+            record_attempts: false,
+        }
+    }
+
+    /// Set the backoff before the first retry and the most that the backoff can grow to, which
+    /// can't be less than the initial backoff.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Set how much the backoff grows by after each attempt, at least 1.
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Set the fraction of each backoff, from `0.` to `1.`, that can be taken off at random.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only retry the errors, as returned by the dispatcher, that `retryable` accepts.
+    pub fn retryable(mut self, retryable: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Set whether requests that time out are retried, disabled by default.
+    pub fn retry_timeouts(mut self, retry_timeouts: bool) -> Self {
+        self.retry_timeouts = retry_timeouts;
+        self
+    }
+
+    /// Record the latency of every attempt in the output, disabled by default.
+    pub fn record_attempts(mut self, record_attempts: bool) -> Self {
+        self.record_attempts = record_attempts;
+        self
+    }
+
+    pub(crate) fn is_retryable(&self, error: &str) -> bool {
+        (self.retryable)(error)
+    }
+
+    /// Build a sampler for the backoffs, seeding the jitter with `seed`.
+    pub(crate) fn backoff_sampler(&self, seed: u64) -> BackoffSampler {
+        BackoffSampler {
+            policy: self.clone(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+/// Draws backoffs for a single client.
+pub(crate) struct BackoffSampler {
+    policy: RetryPolicy,
+    rng: StdRng,
+}
+
+impl BackoffSampler {
+    pub(crate) fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// The backoff to wait after the given attempt, counting from 1.
+    pub(crate) fn next(&mut self, attempt: u32) -> Duration {
+        let policy = &self.policy;
+        let growth = policy.multiplier.powi(attempt.saturating_sub(1) as i32);
+        // grown in seconds, as a long backoff can grow past the longest duration
+        let backoff = Duration::try_from_secs_f64(policy.initial_backoff.as_secs_f64() * growth)
+            .map_or(policy.max_backoff, |backoff| {
+                backoff.min(policy.max_backoff)
+            });
+        let jitter = policy.jitter * self.rng.gen::<f64>();
+        // only rounding can take the shortened backoff past the longest duration
+        Duration::try_from_secs_f64(backoff.as_secs_f64() * (1. - jitter)).unwrap_or(backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_to_max() {
+        let policy = RetryPolicy::new(10)
+            .backoff(Duration::from_millis(10), Duration::from_millis(50))
+            .jitter(0.);
+        let mut sampler = policy.backoff_sampler(0);
+        let backoffs: Vec<_> = (1..=5).map(|attempt| sampler.next(attempt)).collect();
+        let millis = |ms| Duration::from_millis(ms);
+        assert_eq!(
+            backoffs,
+            [millis(10), millis(20), millis(40), millis(50), millis(50)]
+        );
+
+        let mut sampler = policy.jitter(0.5).backoff_sampler(0);
+        for attempt in 1..=5 {
+            let backoff = sampler.next(attempt);
+            assert!(backoff >= backoffs[attempt as usize - 1] / 2, "{backoff:?}");
+            assert!(backoff <= backoffs[attempt as usize - 1], "{backoff:?}");
+        }
+    }
+
+    #[test]
+    fn test_backoff_too_long_to_represent() {
+        let policy = RetryPolicy::new(10)
+            .backoff(Duration::MAX, Duration::MAX)
+            .jitter(0.);
+        let mut sampler = policy.backoff_sampler(0);
+        assert!((1..=5).all(|attempt| sampler.next(attempt) == Duration::MAX));
+
+        let policy = RetryPolicy::new(10)
+            .backoff(Duration::from_secs(1), Duration::MAX)
+            .multiplier(f64::MAX)
+            .jitter(0.);
+        let mut sampler = policy.backoff_sampler(0);
+        assert_eq!(sampler.next(1), Duration::from_secs(1));
+        assert_eq!(sampler.next(2), Duration::MAX);
+    }
+}
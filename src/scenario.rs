@@ -0,0 +1,255 @@
+//! Running several kinds of load at once, each with its own dispatchers and options.
+
+use std::{future::Future, pin::Pin};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    client::{Dispatcher, DispatcherGenerator},
+    generate_load,
+    generate_load_sharded,
+    // not imported, as its `next` would clash with that of every iterator
+    input,
+    output_sink::OutputSink,
+    report::ScenarioReport,
+    LoadOptions,
+    Output,
+    RunReport,
+};
+
+type RunScenario<O> = Box<
+    dyn FnOnce(ScenarioSink<O>) -> Pin<Box<dyn Future<Output = Result<RunReport, String>> + Send>>
+        + Send,
+>;
+
+/// A named part of a run, such as the readers or the writers, generating its own load with its
+/// own input generator, dispatchers and options.
+///
+/// Scenarios are run together with [`generate_scenarios`].
+pub struct Scenario<O> {
+    name: String,
+    output_buffer: usize,
+    run: RunScenario<O>,
+}
+
+impl<O: Serialize + Default + Send + 'static> Scenario<O> {
+    /// A scenario that runs like [`generate_load`].
+    pub fn new<D, I>(
+        name: impl Into<String>,
+        options: LoadOptions,
+        input_generator: I,
+        dispatcher_generator: D,
+    ) -> Self
+    where
+        D: DispatcherGenerator + Send + 'static,
+        D::Dispatcher: Dispatcher<Output = O>,
+        I: input::AsyncInputGenerator<Input = <D::Dispatcher as Dispatcher>::Input>
+            + Send
+            + 'static,
+    {
+        Self {
+            name: name.into(),
+            output_buffer: options.output_buffer(),
+            run: Box::new(move |mut sink| {
+                Box::pin(async move {
+                    generate_load(options, input_generator, dispatcher_generator, &mut sink)
+                        .await
+                        .map_err(|error| error.to_string())
+                })
+            }),
+        }
+    }
+
+    /// A scenario that runs like [`generate_load_sharded`], split across the shards in its
+    /// options.
+    pub fn sharded<D, I, FI, FD>(
+        name: impl Into<String>,
+        options: LoadOptions,
+        input_generators: FI,
+        dispatcher_generators: FD,
+    ) -> Self
+    where
+        D: DispatcherGenerator + Send + 'static,
+        D::Dispatcher: Dispatcher<Output = O>,
+        I: input::AsyncInputGenerator<Input = <D::Dispatcher as Dispatcher>::Input>
+            + Send
+            + 'static,
+        FI: FnMut(u32) -> I + Send + 'static,
+        FD: FnMut(u32) -> D + Send + 'static,
+    {
+        Self {
+            name: name.into(),
+            output_buffer: options.output_buffer(),
+            run: Box::new(move |mut sink| {
+                Box::pin(async move {
+                    let report = generate_load_sharded(
+                        options,
+                        input_generators,
+                        dispatcher_generators,
+                        &mut sink,
+                    )
+                    .await;
+                    Ok(report)
+                })
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Tags outputs with the name of their scenario on their way to the shared sink.
+struct ScenarioSink<O> {
+    name: String,
+    outputs: async_channel::Sender<Output<O>>,
+}
+
+#[async_trait]
+impl<O: Send + 'static> OutputSink<O> for ScenarioSink<O> {
+    async fn send(&mut self, mut output: Output<O>) {
+        output.core.scenario = Some(self.name.clone());
+        // the shared sink only goes away once every scenario has finished
+        let _ = self.outputs.send(output).await;
+    }
+}
+
+/// Generate the load of several scenarios at once, sending all of their outputs to the one sink.
+///
+/// Each scenario is a run of its own, following its own options, such as its rate or load
+/// profile, with its outputs tagged with its name. Scenarios share the output type so that they
+/// can share the sink, an enum can hold the data of scenarios that record different things.
+///
+/// Returns the report of each scenario, in the order they were given. A scenario that panics, or
+/// whose options can't be run, gets the error in its report without stopping the others.
+pub async fn generate_scenarios<O: Send + 'static, S: OutputSink<O>>(
+    scenarios: Vec<Scenario<O>>,
+    output_sink: &mut S,
+) -> Vec<ScenarioReport> {
+    let output_buffer = scenarios
+        .iter()
+        .map(|scenario| scenario.output_buffer)
+        .max()
+        .unwrap_or(1);
+    let (output_sender, output_receiver) = async_channel::bounded(output_buffer);
+    let tasks: Vec<_> = scenarios
+        .into_iter()
+        .map(|Scenario { name, run, .. }| {
+            info!(scenario = %name, "Starting scenario");
+            let sink = ScenarioSink {
+                name: name.clone(),
+                outputs: output_sender.clone(),
+            };
+            (name, tokio::spawn(run(sink)))
+        })
+        .collect();
+    drop(output_sender);
+
+    // finishes once every scenario has dropped its sink
+    while let Ok(output) = output_receiver.recv().await {
+        output_sink.send(output).await;
+    }
+
+    let mut reports = Vec::with_capacity(tasks.len());
+    for (scenario, task) in tasks {
+        let report = task.await.unwrap_or_else(|error| Err(error.to_string()));
+        if let Err(error) = &report {
+            warn!(%scenario, %error, "Scenario failed");
+        }
+        reports.push(ScenarioReport { scenario, report });
+    }
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        clock::TokioClock,
+        testing::{FnDispatcher, VecOutputSink},
+        StopReason,
+    };
+
+    /// Executes any kind of input.
+    fn store_dispatcher<I: Send + 'static>() -> FnDispatcher<I, ()> {
+        FnDispatcher::new(|_| async {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            Ok(())
+        })
+    }
+
+    fn options(rate: u64) -> LoadOptions {
+        LoadOptions::builder()
+            .rate(rate)
+            .total(rate)
+            .clock(TokioClock::new(0))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scenarios_share_sink() {
+        let scenarios = vec![
+            Scenario::new(
+                "readers",
+                options(80),
+                std::iter::repeat(1_u64),
+                store_dispatcher().generator(),
+            ),
+            Scenario::sharded(
+                "writers",
+                options(20),
+                |_| std::iter::repeat("value".to_owned()),
+                |_| store_dispatcher().generator(),
+            ),
+        ];
+        let mut sink = VecOutputSink::default();
+        let reports = generate_scenarios(scenarios, &mut sink).await;
+
+        let names: Vec<_> = reports.iter().map(|r| r.scenario.as_str()).collect();
+        assert_eq!(names, ["readers", "writers"]);
+        for (report, sent) in reports.iter().zip([80, 20]) {
+            let report = report.report.as_ref().unwrap();
+            assert_eq!(report.stop_reason, StopReason::Total);
+            assert_eq!(report.inputs_sent, sent);
+        }
+        let count = |name: &str| {
+            sink.0
+                .iter()
+                .filter(|o| o.core.scenario.as_deref() == Some(name))
+                .count()
+        };
+        assert_eq!((count("readers"), count("writers")), (80, 20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_scenario_keeps_other_reports() {
+        let scenarios = vec![
+            Scenario::new(
+                "broken",
+                options(10),
+                std::iter::from_fn(|| -> Option<u64> { panic!("no inputs") }),
+                store_dispatcher().generator(),
+            ),
+            Scenario::new(
+                "working",
+                options(10),
+                std::iter::repeat(1_u64),
+                store_dispatcher().generator(),
+            ),
+        ];
+        let mut sink = VecOutputSink::default();
+        let reports = generate_scenarios(scenarios, &mut sink).await;
+
+        let broken = reports[0].report.as_ref().unwrap();
+        assert_eq!(broken.stop_reason, StopReason::Failed);
+        assert!(broken.failed_joins[0].error.contains("no inputs"));
+        assert_eq!(reports[1].report.as_ref().unwrap().inputs_sent, 10);
+        assert_eq!(sink.0.len(), 10);
+    }
+}
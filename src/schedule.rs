@@ -0,0 +1,433 @@
+use std::time::Duration;
+
+use tokio::time::{sleep_until, Instant};
+
+use crate::{
+    arrival::ArrivalProcess,
+    options::{MissedTickBehavior, Pacing},
+    profile::LoadProfile,
+    report::{ScheduleReport, ScheduleStats},
+};
+
+/// Longest step taken through the profile before reading its rate again.
+const STEP: Duration = Duration::from_millis(10);
+
+/// How far ahead the schedule is worked out while waiting for the rate to rise above zero.
+const LOOKAHEAD: Duration = Duration::from_secs(1);
+
+/// How late a request can be before it counts as a missed tick.
+const MISSED_TICK_TOLERANCE: Duration = Duration::from_millis(1);
+
+/// How long before a request is due that spin pacing stops sleeping, enough to cover the timer
+/// waking late.
+const SPIN_BEFORE: Duration = Duration::from_millis(2);
+
+/// Works out when each request should be sent, following the load profile with gaps drawn from
+/// the arrival process.
+///
+/// Each gap is converted into an amount of work, the number of requests that the current rate
+/// would have sent in that time, which is then used up by stepping through the profile.
+/// This lets a change in rate take effect part way through a long gap, rather than only at the
+/// next request.
+pub(crate) struct Schedule {
+    profile: LoadProfile,
+    arrival: Box<dyn ArrivalProcess>,
+    start: Instant,
+    /// Time up to which the schedule has been worked out.
+    cursor: Instant,
+    /// Work left before the next request is due, if a gap has been drawn.
+    remaining: Option<f64>,
+    /// Fraction of the profile's rate that this schedule follows.
+    share: f64,
+    /// Fraction of the first gap to wait for, offsetting shards from each other.
+    offset: f64,
+    /// The offset to use again when the rate is set.
+    shard_offset: f64,
+    /// The rate last set part way through the run, with the time since the start that it takes
+    /// effect, or `None` to go back to the profile.
+    rate_set: (Duration, Option<f64>),
+    /// Requests expected before the rate was last set, so that earlier rates needn't be kept.
+    expected_before: f64,
+}
+
+impl Schedule {
+    pub(crate) fn new(
+        profile: LoadProfile,
+        arrival: Box<dyn ArrivalProcess>,
+        start: Instant,
+    ) -> Self {
+        Self {
+            profile,
+            arrival,
+            start,
+            cursor: start,
+            remaining: None,
+            share: 1.,
+            offset: 1.,
+            shard_offset: 1.,
+            rate_set: (Duration::ZERO, None),
+            expected_before: 0.,
+        }
+    }
+
+    /// Follow this shard's share of the profile, with its first request offset so that evenly
+    /// spaced arrivals from each shard interleave rather than landing together.
+    pub(crate) fn shard(mut self, shard: u32, shards: u32) -> Self {
+        self.share = 1. / shards as f64;
+        self.offset = (shard + 1) as f64 / shards as f64;
+        self.shard_offset = self.offset;
+        self
+    }
+
+    /// Follow a constant rate from `at`, or the profile again if `None`, starting a fresh gap to
+    /// the next request.
+    pub(crate) fn set_rate(&mut self, at: Instant, rate: Option<f64>) {
+        let at = at.max(self.start);
+        let from = at - self.start;
+        // a delayed schedule can go back to before the last rate was set, which it then overtakes
+        let (set_from, _) = self.rate_set;
+        if from > set_from {
+            self.expected_before += self.expected_between(set_from, from);
+        }
+        self.rate_set = (from, rate);
+        self.cursor = at;
+        self.remaining = None;
+        self.offset = self.shard_offset;
+    }
+
+    fn rate_at(&self, elapsed: Duration) -> f64 {
+        let rate = match self.rate_set {
+            (from, Some(rate)) if from <= elapsed => rate,
+            _ => self.profile.rate_at(elapsed),
+        };
+        rate * self.share
+    }
+
+    /// Whether the profile has settled on a rate of zero for good, without a rate set to
+    /// override it, so that nothing more will be due.
+    pub(crate) fn profile_ended(&self) -> bool {
+        let (_, rate) = self.rate_set;
+        rate.is_none()
+            && self
+                .profile
+                .ends_at()
+                .is_some_and(|end| self.cursor >= self.start + end)
+    }
+
+    /// Time up to which the schedule has been worked out.
+    pub(crate) fn cursor(&self) -> Instant {
+        self.cursor
+    }
+
+    /// Push the rest of the schedule, including the profile, back by `by`.
+    pub(crate) fn delay(&mut self, by: Duration) {
+        self.start += by;
+        self.cursor += by;
+    }
+
+    /// Number of requests that the profile expects in the first `elapsed` of the run.
+    pub(crate) fn expected(&self, elapsed: Duration) -> f64 {
+        let (set_from, _) = self.rate_set;
+        self.expected_before + self.expected_between(set_from, elapsed)
+    }
+
+    /// Number of requests expected between `from` and `to` since the start, following the rate
+    /// last set.
+    fn expected_between(&self, from: Duration, to: Duration) -> f64 {
+        let mut expected = 0.;
+        let mut at = from;
+        while at < to {
+            let step = STEP.min(to - at);
+            expected += self.rate_at(at) * step.as_secs_f64();
+            at += step;
+        }
+        expected
+    }
+
+    /// Work out when the next request is due, stepping through the profile up to `horizon`.
+    ///
+    /// Returns `None` if nothing is due before the horizon, in which case this should be called
+    /// again once the cursor has passed.
+    pub(crate) fn next(&mut self, horizon: Instant) -> Option<Instant> {
+        while self.cursor < horizon {
+            let rate = self.rate_at(self.cursor - self.start);
+            if rate <= 0. {
+                self.cursor += STEP;
+                continue;
+            }
+            let remaining = match self.remaining {
+                Some(remaining) => remaining,
+                None => {
+                    let work = self.arrival.next_gap(rate).as_secs_f64() * rate * self.offset;
+                    self.offset = 1.;
+                    work
+                }
+            };
+            let needed = Duration::from_secs_f64(remaining / rate);
+            if needed <= STEP {
+                self.cursor += needed;
+                self.remaining = None;
+                return Some(self.cursor);
+            }
+            self.cursor += STEP;
+            self.remaining = Some(remaining - rate * STEP.as_secs_f64());
+        }
+        None
+    }
+}
+
+/// Waits for each request on a [`Schedule`] to become due, keeping track of how well it keeps up.
+pub(crate) struct Pacer {
+    schedule: Schedule,
+    missed_ticks: MissedTickBehavior,
+    pacing: Pacing,
+    start: Instant,
+    stats: ScheduleStats,
+    /// Whether to stop once the profile has ended, rather than waiting for a rate to be set.
+    stop_at_profile_end: bool,
+}
+
+impl Pacer {
+    pub(crate) fn new(
+        schedule: Schedule,
+        missed_ticks: MissedTickBehavior,
+        pacing: Pacing,
+    ) -> Self {
+        Self {
+            start: schedule.cursor(),
+            schedule,
+            missed_ticks,
+            pacing,
+            stats: ScheduleStats::default(),
+            stop_at_profile_end: false,
+        }
+    }
+
+    /// Stop once the profile settles on a rate of zero for good, if the run has no end of its
+    /// own, as nothing more would ever be due.
+    pub(crate) fn stop_at_profile_end(mut self) -> Self {
+        self.stop_at_profile_end = true;
+        self
+    }
+
+    /// Whether the profile has ended, so that nothing more will be due.
+    pub(crate) fn profile_ended(&self) -> bool {
+        self.schedule.profile_ended()
+    }
+
+    /// When the schedule started.
+    pub(crate) fn start(&self) -> Instant {
+        self.start
+    }
+
+    /// Wait until the next request is due, returning the time it was scheduled for, or `None` if
+    /// the run reaches its end first, or the profile ends when stopping at the end of the
+    /// profile.
+    ///
+    /// Requests that are already late by more than [`MISSED_TICK_TOLERANCE`] are handled
+    /// following the missed tick behaviour.
+    pub(crate) async fn wait_for_next(&mut self, end: Option<Instant>) -> Option<Instant> {
+        let past_end = |instant: Instant| end.is_some_and(|end| instant >= end);
+        loop {
+            let scheduled = loop {
+                let mut horizon = Instant::now().max(self.schedule.cursor()) + LOOKAHEAD;
+                if let Some(end) = end {
+                    horizon = horizon.min(end);
+                }
+                if let Some(scheduled) = self.schedule.next(horizon) {
+                    break scheduled;
+                }
+                if past_end(self.schedule.cursor()) {
+                    break self.schedule.cursor();
+                }
+                if self.stop_at_profile_end && end.is_none() && self.schedule.profile_ended() {
+                    return None;
+                }
+                // nothing due yet as the rate is zero, wait for it to pick up again
+                sleep_until(self.schedule.cursor()).await;
+            };
+            if let Some(end) = end.filter(|_| past_end(scheduled)) {
+                sleep_until(end).await;
+                return None;
+            }
+
+            let now = Instant::now();
+            if now.saturating_duration_since(scheduled) > MISSED_TICK_TOLERANCE {
+                self.stats.record_missed_tick();
+                match self.missed_ticks {
+                    MissedTickBehavior::Burst => return Some(scheduled),
+                    MissedTickBehavior::Delay => {
+                        self.schedule.delay(now - scheduled);
+                        return Some(now);
+                    }
+                    MissedTickBehavior::Skip => continue,
+                }
+            }
+            pace(self.pacing, scheduled).await;
+            return Some(scheduled);
+        }
+    }
+
+    /// Follow a constant rate from now, or the profile again if `None`.
+    pub(crate) fn set_rate(&mut self, rate: Option<f64>) {
+        self.schedule.set_rate(Instant::now(), rate);
+    }
+
+    /// Record a request scheduled at `scheduled` being handed to a client at `sent`.
+    pub(crate) fn record_send(&mut self, scheduled: Instant, sent: Instant) {
+        self.stats.record_send(scheduled, sent);
+    }
+
+    /// Finish the report on how well the run kept to the schedule.
+    pub(crate) fn report(self) -> ScheduleReport {
+        let elapsed = self.start.elapsed();
+        let expected = self.schedule.expected(elapsed);
+        self.stats.report(expected, elapsed)
+    }
+}
+
+/// Wait until `until`, following the pacing.
+async fn pace(pacing: Pacing, until: Instant) {
+    match pacing {
+        Pacing::Sleep => sleep_until(until).await,
+        Pacing::Spin => {
+            if let Some(wake) = until.checked_sub(SPIN_BEFORE) {
+                if wake > Instant::now() {
+                    sleep_until(wake).await;
+                }
+            }
+            // let other tasks on this thread run while spinning
+            while Instant::now() < until {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arrival::Constant;
+
+    /// The gaps between the times that the pacer hands out the first `count` inputs, at 5000 per
+    /// second.
+    ///
+    /// Time is advanced by hand in 10µs steps, as spinning never lets paused time skip ahead.
+    async fn paced_gaps(pacing: Pacing, count: usize) -> Vec<Duration> {
+        let schedule = Schedule::new(
+            LoadProfile::Constant(5000.),
+            Box::new(Constant),
+            Instant::now(),
+        );
+        let mut pacer = Pacer::new(schedule, MissedTickBehavior::Burst, pacing);
+        let sends = async {
+            let mut sent = Vec::with_capacity(count);
+            for _ in 0..count {
+                pacer.wait_for_next(None).await;
+                sent.push(Instant::now());
+            }
+            sent
+        };
+        let clock = async {
+            loop {
+                tokio::time::advance(Duration::from_micros(10)).await;
+            }
+        };
+        let sent = tokio::select! {
+            sent = sends => sent,
+            () = clock => unreachable!(),
+        };
+        sent.windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
+
+    fn count_until(schedule: &mut Schedule, end: Instant) -> usize {
+        std::iter::from_fn(|| schedule.next(end)).count()
+    }
+
+    #[test]
+    fn test_follows_rate_changes() {
+        let start = Instant::now();
+        let profile = LoadProfile::Piecewise(vec![
+            (Duration::from_secs(1), 100.),
+            (Duration::from_secs(1), 0.),
+            (Duration::from_secs(1), 1000.),
+        ]);
+        let mut schedule = Schedule::new(profile, Box::new(Constant), start);
+
+        assert_eq!(
+            count_until(&mut schedule, start + Duration::from_secs(1)),
+            100
+        );
+        assert_eq!(
+            count_until(&mut schedule, start + Duration::from_secs(2)),
+            0
+        );
+        let count = count_until(&mut schedule, start + Duration::from_secs(3));
+        assert!((999..=1001).contains(&count), "{count}");
+    }
+
+    #[test]
+    fn test_set_rate_overrides_profile() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut schedule = Schedule::new(LoadProfile::Constant(100.), Box::new(Constant), start);
+        assert_eq!(count_until(&mut schedule, start + second), 100);
+
+        schedule.set_rate(start + second, Some(0.));
+        assert_eq!(count_until(&mut schedule, start + second * 2), 0);
+        schedule.set_rate(start + second * 2, Some(1000.));
+        let count = count_until(&mut schedule, start + second * 3);
+        assert!((999..=1001).contains(&count), "{count}");
+        schedule.set_rate(start + second * 3, None);
+        let count = count_until(&mut schedule, start + second * 4);
+        assert!((99..=101).contains(&count), "{count}");
+
+        let expected = schedule.expected(second * 4);
+        assert!((1199. ..=1201.).contains(&expected), "{expected}");
+    }
+
+    #[test]
+    fn test_shards_interleave() {
+        let start = Instant::now();
+        let end = start + Duration::from_millis(20);
+        let mut due: Vec<_> = (0..4)
+            .flat_map(|shard| {
+                let mut schedule =
+                    Schedule::new(LoadProfile::Constant(1000.), Box::new(Constant), start)
+                        .shard(shard, 4);
+                // the schedule can overshoot the horizon by up to a step
+                std::iter::from_fn(move || schedule.next(end))
+                    .take_while(|at| *at <= end)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        due.sort();
+
+        assert_eq!(due.len(), 20);
+        for (i, at) in due.iter().enumerate() {
+            let expected = Duration::from_millis(i as u64 + 1);
+            let at = *at - start;
+            assert!(
+                at.max(expected) - at.min(expected) < Duration::from_micros(1),
+                "{i}"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_spin_pacing_spaces_inputs_evenly() {
+        // spinning hands out each input within a step of when it is due
+        let gaps = paced_gaps(Pacing::Spin, 50).await;
+        let step = Duration::from_micros(10);
+        let gap = Duration::from_micros(200);
+        assert!(
+            gaps.iter().all(|g| (gap - step..=gap + step).contains(g)),
+            "{gaps:?}"
+        );
+
+        // while sleeping hands out the inputs due in each millisecond of the timer together
+        let gaps = paced_gaps(Pacing::Sleep, 50).await;
+        let together = gaps.iter().filter(|g| **g < step).count();
+        assert!(together >= gaps.len() * 3 / 4, "{gaps:?}");
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use crate::client::Request;
+
+/// Routes the inputs of each session to the same client task, so that they are executed in the
+/// order they were generated.
+///
+/// Each client task has its own queue of session inputs, alongside the queue shared by all of
+/// the clients. A session is assigned to the task with the fewest sessions when its first input
+/// comes along, and stays with that task until it ends.
+pub(crate) struct Sessions<I> {
+    capacity: usize,
+    /// Each task's queue, along with the number of sessions assigned to it.
+    queues: Vec<(async_channel::Sender<Request<I>>, u32)>,
+    /// The task that each session was assigned to.
+    assigned: HashMap<u64, usize>,
+}
+
+impl<I> Sessions<I> {
+    /// Route sessions to queues holding up to `capacity` inputs each.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queues: Vec::new(),
+            assigned: HashMap::new(),
+        }
+    }
+
+    /// Add a queue for a new task, returning the end for the task to take inputs from.
+    pub(crate) fn add_task(&mut self) -> async_channel::Receiver<Request<I>> {
+        let (sender, receiver) = async_channel::bounded(self.capacity);
+        self.queues.push((sender, 0));
+        receiver
+    }
+
+    /// Whether any task has been given a queue yet.
+    pub(crate) fn has_tasks(&self) -> bool {
+        !self.queues.is_empty()
+    }
+
+    /// Whether the session has already been assigned to a task.
+    pub(crate) fn is_assigned(&self, session: u64) -> bool {
+        self.assigned.contains_key(&session)
+    }
+
+    /// The queue of the task that the session is assigned to, assigning it if it is new.
+    ///
+    /// # Panics
+    ///
+    /// If there are no tasks to assign a new session to.
+    pub(crate) fn queue(&mut self, session: u64) -> &async_channel::Sender<Request<I>> {
+        let queues = &mut self.queues;
+        let task = *self.assigned.entry(session).or_insert_with(|| {
+            let (task, (_, sessions)) = queues
+                .iter_mut()
+                .enumerate()
+                .min_by_key(|(_, (_, sessions))| *sessions)
+                .expect("No client tasks to assign the session to");
+            *sessions += 1;
+            task
+        });
+        &self.queues[task].0
+    }
+
+    /// Release the session from its task, once its last input has been routed.
+    pub(crate) fn end(&mut self, session: u64) {
+        if let Some(task) = self.assigned.remove(&session) {
+            self.queues[task].1 -= 1;
+        }
+    }
+
+    /// Close every task's queue, letting the tasks finish once they have taken what is left.
+    pub(crate) fn close(&self) {
+        for (queue, _) in &self.queues {
+            queue.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(sessions: &mut Sessions<u64>, session: u64) {
+        let request = Request {
+            input: session,
+            scheduled_ns: None,
+            phase: Default::default(),
+            session: Some(session),
+        };
+        sessions.queue(session).try_send(request).unwrap();
+    }
+
+    fn received(receivers: [async_channel::Receiver<Request<u64>>; 2]) -> [Vec<u64>; 2] {
+        receivers.map(|receiver| {
+            std::iter::from_fn(|| receiver.try_recv().ok())
+                .map(|request| request.input)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_sessions_stay_with_their_task() {
+        let mut sessions = Sessions::new(10);
+        let receivers = [sessions.add_task(), sessions.add_task()];
+        for session in [1, 2, 1, 3, 2, 1] {
+            route(&mut sessions, session);
+        }
+        assert!(sessions.is_assigned(3));
+        assert!(!sessions.is_assigned(4));
+
+        sessions.close();
+        // new sessions go to whichever task has the fewest
+        assert_eq!(received(receivers), [vec![1, 1, 3, 1], vec![2, 2]]);
+    }
+
+    #[test]
+    fn test_ended_sessions_are_released() {
+        let mut sessions = Sessions::new(10);
+        let receivers = [sessions.add_task(), sessions.add_task()];
+        route(&mut sessions, 1);
+        route(&mut sessions, 2);
+        sessions.end(1);
+        assert!(!sessions.is_assigned(1));
+        assert_eq!(sessions.assigned.len(), 1);
+        // the first task has no sessions left, so it takes the next ones
+        route(&mut sessions, 3);
+        sessions.end(3);
+        route(&mut sessions, 4);
+
+        sessions.close();
+        assert_eq!(received(receivers), [vec![1, 3, 4], vec![2]]);
+    }
+}
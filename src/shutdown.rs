@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+
+/// How far a run has got in shutting down, each state following on from the last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ShutdownState {
+    Running,
+    /// Stop generating new inputs and let the in-flight requests finish.
+    Draining,
+    /// Stop waiting for in-flight requests, clients should hand back what they have collected.
+    Cancelled,
+    /// Stop immediately, without collecting any more outputs.
+    Aborted,
+}
+
+/// Coordinates stopping a run early, from signals or the run itself.
+pub(crate) struct Shutdown {
+    sender: Arc<watch::Sender<ShutdownState>>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl Shutdown {
+    /// Create a new shutdown, listening for SIGINT and SIGTERM if `handle_signals` is set.
+    ///
+    /// The first signal starts draining the run and a second aborts it.
+    pub(crate) fn new(handle_signals: bool) -> Self {
+        let (sender, _) = watch::channel(ShutdownState::Running);
+        let sender = Arc::new(sender);
+        let listener = handle_signals.then(|| {
+            let sender = Arc::clone(&sender);
+            tokio::spawn(async move {
+                wait_for_signal().await;
+                info!("Received signal, draining load, signal again to abort");
+                advance(&sender, ShutdownState::Draining);
+                wait_for_signal().await;
+                warn!("Received second signal, aborting");
+                advance(&sender, ShutdownState::Aborted);
+            })
+        });
+        Self { sender, listener }
+    }
+
+    pub(crate) fn watch(&self) -> ShutdownWatch {
+        ShutdownWatch(self.sender.subscribe())
+    }
+
+    /// A handle for cancelling the run from elsewhere, such as from a shard on another thread.
+    pub(crate) fn canceller(&self) -> Canceller {
+        Canceller(Arc::clone(&self.sender))
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            listener.abort();
+        }
+    }
+}
+
+/// Move the state on, never back.
+fn advance(sender: &watch::Sender<ShutdownState>, state: ShutdownState) {
+    sender.send_if_modified(|current| {
+        let modified = state > *current;
+        if modified {
+            *current = state;
+        }
+        modified
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(error) => {
+            warn!(%error, "Failed to listen for SIGTERM");
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Cancels the in-flight requests of a run.
+#[derive(Clone)]
+pub(crate) struct Canceller(Arc<watch::Sender<ShutdownState>>);
+
+impl Canceller {
+    /// Tell the clients to stop waiting for their in-flight requests.
+    pub(crate) fn cancel(&self) {
+        advance(&self.0, ShutdownState::Cancelled);
+    }
+}
+
+/// Watches the shutdown state of a run.
+#[derive(Clone)]
+pub(crate) struct ShutdownWatch(watch::Receiver<ShutdownState>);
+
+impl ShutdownWatch {
+    /// Wait until the shutdown reaches at least `state`.
+    pub(crate) async fn reached(&mut self, state: ShutdownState) {
+        if self.0.wait_for(|current| *current >= state).await.is_err() {
+            // the run has finished with its shutdown, so nothing will change
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel_reaches_earlier_states() {
+        let shutdown = Shutdown::new(false);
+        let mut watch = shutdown.watch();
+        shutdown.canceller().cancel();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            watch.reached(ShutdownState::Draining),
+        )
+        .await
+        .unwrap();
+
+        let aborted = tokio::time::timeout(
+            Duration::from_millis(10),
+            watch.reached(ShutdownState::Aborted),
+        )
+        .await;
+        assert!(aborted.is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Dispatchers, generators and sinks shared by the tests.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    client::{Dispatcher, DispatcherGenerator},
+    input::InputGenerator,
+    output_sink::OutputSink,
+    Output,
+};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type Execute<I, O> = Arc<dyn Fn(I) -> BoxFuture<Result<O, String>> + Send + Sync>;
+type ExecuteBatch<I, O> = Arc<dyn Fn(Vec<I>) -> BoxFuture<Vec<Result<O, String>>> + Send + Sync>;
+type Timeout<I> = Arc<dyn Fn(&I) -> Option<Duration> + Send + Sync>;
+type CopyInput<I> = Arc<dyn Fn(&I) -> I + Send + Sync>;
+type Session<T> = Arc<dyn Fn(&T) -> Option<u64> + Send + Sync>;
+type EndsSession<T> = Arc<dyn Fn(&T) -> bool + Send + Sync>;
+
+/// Executes each input with a closure, shared by every copy of the dispatcher.
+pub(crate) struct FnDispatcher<I, O> {
+    execute: Execute<I, O>,
+    execute_batch: Option<ExecuteBatch<I, O>>,
+    timeout: Option<Timeout<I>>,
+    retry_input: Option<CopyInput<I>>,
+    concurrent: bool,
+}
+
+impl<I, O> Clone for FnDispatcher<I, O> {
+    fn clone(&self) -> Self {
+        Self {
+            execute: Arc::clone(&self.execute),
+            execute_batch: self.execute_batch.clone(),
+            timeout: self.timeout.clone(),
+            retry_input: self.retry_input.clone(),
+            concurrent: self.concurrent,
+        }
+    }
+}
+
+impl<I: Send + 'static, O: Send + Default + 'static> FnDispatcher<I, O> {
+    pub(crate) fn new<F, Fut>(execute: F) -> Self
+    where
+        F: Fn(I) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<O, String>> + Send + 'static,
+    {
+        Self {
+            execute: Arc::new(move |input| Box::pin(execute(input))),
+            execute_batch: None,
+            timeout: None,
+            retry_input: None,
+            concurrent: false,
+        }
+    }
+
+    /// Execute batches with a closure too, rather than one input at a time.
+    pub(crate) fn batched<F, Fut>(mut self, execute_batch: F) -> Self
+    where
+        F: Fn(Vec<I>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Vec<Result<O, String>>> + Send + 'static,
+    {
+        self.execute_batch = Some(Arc::new(move |inputs| Box::pin(execute_batch(inputs))));
+        self
+    }
+
+    /// Override the timeout of inputs with a closure.
+    pub(crate) fn timeout(
+        mut self,
+        timeout: impl Fn(&I) -> Option<Duration> + Send + Sync + 'static,
+    ) -> Self {
+        self.timeout = Some(Arc::new(timeout));
+        self
+    }
+
+    /// Retry inputs with clones of them.
+    pub(crate) fn retrying(mut self) -> Self
+    where
+        I: Clone,
+    {
+        self.retry_input = Some(Arc::new(I::clone));
+        self
+    }
+
+    /// Hand out copies as concurrent handles.
+    pub(crate) fn concurrent(mut self) -> Self {
+        self.concurrent = true;
+        self
+    }
+
+    /// A generator of copies of this dispatcher.
+    pub(crate) fn generator(self) -> FnDispatcherGenerator<impl FnMut() -> Self + Send + 'static> {
+        FnDispatcherGenerator(move || self.clone())
+    }
+}
+
+#[async_trait]
+impl<I: Send + 'static, O: Send + Default + 'static> Dispatcher for FnDispatcher<I, O> {
+    type Input = I;
+    type Output = O;
+
+    async fn execute(&mut self, input: Self::Input) -> Result<Self::Output, String> {
+        (self.execute)(input).await
+    }
+
+    async fn execute_batch(
+        &mut self,
+        inputs: Vec<Self::Input>,
+    ) -> Vec<Result<Self::Output, String>> {
+        match &self.execute_batch {
+            Some(execute_batch) => execute_batch(inputs).await,
+            None => {
+                let mut results = Vec::with_capacity(inputs.len());
+                for input in inputs {
+                    results.push((self.execute)(input).await);
+                }
+                results
+            }
+        }
+    }
+
+    fn timeout(&self, input: &Self::Input) -> Option<Duration> {
+        self.timeout.as_ref().and_then(|timeout| timeout(input))
+    }
+
+    fn retry_input(&self, input: &Self::Input) -> Option<Self::Input> {
+        self.retry_input.as_ref().map(|copy| copy(input))
+    }
+
+    fn concurrent_handle(&self) -> Option<Self> {
+        self.concurrent.then(|| self.clone())
+    }
+}
+
+impl<I: Send + 'static> FnDispatcher<I, ()> {
+    /// Executes every input successfully, straight away.
+    pub(crate) fn noop() -> Self {
+        Self::new(|_| async { Ok(()) })
+    }
+}
+
+/// Sleeps for the first duration of each input, with the second overriding the timeout.
+pub(crate) fn sleep_dispatcher() -> FnDispatcher<(Duration, Option<Duration>), ()> {
+    FnDispatcher::new(|(sleep, _)| async move {
+        tokio::time::sleep(sleep).await;
+        Ok(())
+    })
+    .timeout(|(_, timeout)| *timeout)
+}
+
+/// Panics on the inputs that ask it to.
+pub(crate) fn panic_dispatcher() -> FnDispatcher<bool, ()> {
+    FnDispatcher::new(|panic| async move {
+        if panic {
+            panic!("boom");
+        }
+        Ok(())
+    })
+}
+
+/// Fails with the error until it has failed the given number of times.
+pub(crate) fn flaky_dispatcher() -> FnDispatcher<(Arc<AtomicU32>, &'static str), ()> {
+    FnDispatcher::new(|(failures, error): (Arc<AtomicU32>, &str)| async move {
+        let failed = failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |failures| {
+            failures.checked_sub(1)
+        });
+        match failed {
+            Ok(_) => Err(error.to_owned()),
+            Err(_) => Ok(()),
+        }
+    })
+}
+
+/// Takes 5ms over each batch, recording its size and failing the inputs that ask to.
+pub(crate) fn batch_dispatcher(batches: &Arc<Mutex<Vec<usize>>>) -> FnDispatcher<bool, ()> {
+    let batches = Arc::clone(batches);
+    FnDispatcher::new(|_| async { unreachable!("inputs are batched") }).batched(
+        move |inputs: Vec<bool>| {
+            batches.lock().unwrap().push(inputs.len());
+            async move {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                inputs
+                    .into_iter()
+                    .map(|fail| {
+                        if fail {
+                            Err("failed".to_owned())
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .collect()
+            }
+        },
+    )
+}
+
+/// Generates dispatchers with a closure.
+pub(crate) struct FnDispatcherGenerator<F>(pub(crate) F);
+
+impl<D: Dispatcher, F: FnMut() -> D> DispatcherGenerator for FnDispatcherGenerator<F> {
+    type Dispatcher = D;
+
+    fn generate(&mut self) -> Self::Dispatcher {
+        (self.0)()
+    }
+}
+
+/// Generates the inputs of a list, grouping them into sessions if given closures to.
+pub(crate) struct VecInputGenerator<T> {
+    inputs: std::vec::IntoIter<T>,
+    session: Session<T>,
+    ends_session: EndsSession<T>,
+}
+
+impl<T> VecInputGenerator<T> {
+    pub(crate) fn new(inputs: Vec<T>) -> Self {
+        Self {
+            inputs: inputs.into_iter(),
+            session: Arc::new(|_| None),
+            ends_session: Arc::new(|_| false),
+        }
+    }
+
+    /// Put each input into the session given by the closure, ending the session on the inputs
+    /// that `ends_session` picks out.
+    pub(crate) fn sessions(
+        mut self,
+        session: impl Fn(&T) -> Option<u64> + Send + Sync + 'static,
+        ends_session: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.session = Arc::new(session);
+        self.ends_session = Arc::new(ends_session);
+        self
+    }
+}
+
+impl<T: Send> InputGenerator for VecInputGenerator<T> {
+    type Input = T;
+
+    fn next(&mut self) -> Option<Self::Input> {
+        Iterator::next(&mut self.inputs)
+    }
+
+    fn close(self) {}
+
+    fn session(&self, input: &Self::Input) -> Option<u64> {
+        (self.session)(input)
+    }
+
+    fn ends_session(&self, input: &Self::Input) -> bool {
+        (self.ends_session)(input)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct VecOutputSink<O = ()>(pub(crate) Vec<Output<O>>);
+
+#[async_trait]
+impl<O: Send + 'static> OutputSink<O> for VecOutputSink<O> {
+    async fn send(&mut self, output: Output<O>) {
+        self.0.push(output);
+    }
+}